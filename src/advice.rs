@@ -0,0 +1,130 @@
+use crate::{Error, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Longest advice text accepted from users, in characters.
+pub const MAX_LENGTH: usize = 500;
+
+/// Longest tag accepted, in characters.
+pub const MAX_TAG_LENGTH: usize = 32;
+
+/// Longest author name accepted, in characters.
+pub const MAX_AUTHOR_LENGTH: usize = 100;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Advice {
+    pub id: i64,
+    pub advice: String,
+    /// Name of the provider the advice came from, or `user` for advices
+    /// submitted through the API.
+    #[serde(default)]
+    pub source: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub author: Option<String>,
+    /// Id of the API key or subject of the JWT the advice was submitted with,
+    /// if any. Unlike `author`, this is not chosen by the submitter.
+    #[serde(default)]
+    pub created_by: Option<String>,
+    /// Timestamps default to the time of decoding for sources that don't
+    /// carry them, such as upstream slips.
+    #[serde(default = "Utc::now")]
+    pub created_at: DateTime<Utc>,
+    #[serde(default = "Utc::now")]
+    pub updated_at: DateTime<Utc>,
+    /// Bumped by the store on every change; the advice's entity tag.
+    #[serde(default)]
+    pub version: u64,
+}
+
+impl Advice {
+    /// Source of advices submitted through the API.
+    pub const SOURCE_USER: &'static str = "user";
+
+    /// Creates an advice without tags, author or creator, created now.
+    pub fn new(id: i64, advice: impl Into<String>, source: impl Into<String>) -> Self {
+        let now = Utc::now();
+
+        Self {
+            id,
+            advice: advice.into(),
+            source: source.into(),
+            tags: Vec::new(),
+            author: None,
+            created_by: None,
+            created_at: now,
+            updated_at: now,
+            version: 0,
+        }
+    }
+
+    /// Checks user-supplied advice text and returns it trimmed.
+    pub fn validate_text(text: &str) -> Result<String> {
+        let text = text.trim();
+        if text.is_empty() {
+            return Err(Error::BadRequest("advice must not be empty".to_owned()));
+        }
+        if text.chars().count() > MAX_LENGTH {
+            return Err(Error::BadRequest(format!(
+                "advice must be at most {} characters",
+                MAX_LENGTH
+            )));
+        }
+
+        Ok(text.to_owned())
+    }
+
+    /// Checks a user-supplied tag and returns it trimmed and lowercased.
+    ///
+    /// Tags are limited to letters, digits, spaces, `-` and `_`, so they can
+    /// be joined with a separator without escaping.
+    pub fn validate_tag(tag: &str) -> Result<String> {
+        let tag = tag.trim().to_lowercase();
+        if tag.is_empty() {
+            return Err(Error::BadRequest("tags must not be empty".to_owned()));
+        }
+        if tag.chars().count() > MAX_TAG_LENGTH {
+            return Err(Error::BadRequest(format!(
+                "tag {:?} is longer than {} characters",
+                tag, MAX_TAG_LENGTH
+            )));
+        }
+        if !tag
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, ' ' | '-' | '_'))
+        {
+            return Err(Error::BadRequest(format!(
+                "tag {:?} may only contain letters, digits, spaces, '-' and '_'",
+                tag
+            )));
+        }
+
+        Ok(tag)
+    }
+
+    /// Validates every tag, returning them sorted and without duplicates.
+    pub fn validate_tags(tags: &[String]) -> Result<Vec<String>> {
+        let mut tags = tags
+            .iter()
+            .map(|tag| Self::validate_tag(tag))
+            .collect::<Result<Vec<_>>>()?;
+        tags.sort();
+        tags.dedup();
+
+        Ok(tags)
+    }
+
+    /// Checks a user-supplied author name; a blank one means no author.
+    pub fn validate_author(author: &str) -> Result<Option<String>> {
+        let author = author.trim();
+        if author.chars().count() > MAX_AUTHOR_LENGTH {
+            return Err(Error::BadRequest(format!(
+                "author must be at most {} characters",
+                MAX_AUTHOR_LENGTH
+            )));
+        }
+
+        Ok(Some(author.to_owned()).filter(|author| !author.is_empty()))
+    }
+}
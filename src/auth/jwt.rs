@@ -0,0 +1,231 @@
+use super::{Principal, Scope};
+use crate::{Error, Result};
+use anyhow::Context;
+use async_trait::async_trait;
+use axum::extract::{FromRequest, RequestParts};
+use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use std::{
+    path::PathBuf,
+    sync::{PoisonError, RwLock},
+    time::{Duration, Instant},
+};
+
+/// Signature algorithms accepted. Others, `none` and HMAC in particular, are
+/// rejected before looking at the key.
+const ALGORITHMS: &[Algorithm] = &[Algorithm::RS256, Algorithm::ES256];
+
+/// Shortest interval between two reloads of the key set, so that tokens with
+/// made-up key ids cannot make us hammer the identity provider.
+const MIN_RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Claims of a validated token. `exp`, `iss` and `aud` are checked by
+/// `JwtVerifier`, so only the ones handlers may need are kept.
+///
+/// Handlers can take them as an extractor; it fails with
+/// `Error::Unauthorized` when the request carried no token.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Claims {
+    pub sub: String,
+    pub iss: String,
+    pub exp: u64,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub email: Option<String>,
+    /// Space-separated scopes, as in OAuth 2.0.
+    #[serde(default)]
+    pub scope: Option<String>,
+}
+
+impl Claims {
+    /// The principal the token stands for. Scopes we don't know are ignored.
+    pub fn principal(&self) -> Principal {
+        let scopes = self
+            .scope
+            .as_deref()
+            .unwrap_or_default()
+            .split_whitespace()
+            .filter_map(|scope| scope.parse::<Scope>().ok())
+            .collect();
+
+        Principal {
+            id: self.sub.clone(),
+            name: self
+                .name
+                .clone()
+                .or_else(|| self.email.clone())
+                .unwrap_or_else(|| self.sub.clone()),
+            scopes,
+        }
+    }
+}
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for Claims {
+    type Rejection = Error;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self> {
+        req.extensions()
+            .and_then(|extensions| extensions.get::<Claims>())
+            .cloned()
+            .ok_or_else(|| Error::Unauthorized("a bearer token is required".to_owned()))
+    }
+}
+
+/// Where the key set is loaded from.
+#[derive(Debug, Clone)]
+enum Source {
+    File(PathBuf),
+    Url(String),
+}
+
+/// Validates JWTs signed by an identity provider against its JSON Web Key
+/// Set.
+///
+/// The key set is loaded from a file or URL on start, and reloaded when a
+/// token names a key id it doesn't have, as happens when keys are rotated.
+#[derive(Debug)]
+pub struct JwtVerifier {
+    source: Source,
+    client: reqwest::Client,
+    issuer: String,
+    audience: String,
+    keys: RwLock<Keys>,
+    /// Held while reloading, so concurrent misses reload only once.
+    reloading: tokio::sync::Mutex<()>,
+}
+
+#[derive(Debug)]
+struct Keys {
+    set: JwkSet,
+    loaded_at: Instant,
+}
+
+impl JwtVerifier {
+    /// Loads the key set from `jwks`, an http(s) URL or a file path.
+    pub async fn load(
+        jwks: &str,
+        issuer: impl Into<String>,
+        audience: impl Into<String>,
+    ) -> anyhow::Result<Self> {
+        let source = if jwks.starts_with("http://") || jwks.starts_with("https://") {
+            Source::Url(jwks.to_owned())
+        } else {
+            Source::File(jwks.into())
+        };
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(5))
+            .user_agent(concat!("advices-api/", env!("CARGO_PKG_VERSION")))
+            .build()?;
+        let set = fetch(&source, &client).await?;
+
+        Ok(Self {
+            source,
+            client,
+            issuer: issuer.into(),
+            audience: audience.into(),
+            keys: RwLock::new(Keys {
+                set,
+                loaded_at: Instant::now(),
+            }),
+            reloading: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    /// Checks the signature, expiry, issuer and audience of `token` and
+    /// returns its claims.
+    pub async fn verify(&self, token: &str) -> Result<Claims> {
+        let header = jsonwebtoken::decode_header(token)
+            .map_err(|error| Error::Unauthorized(format!("invalid token: {}", error)))?;
+        if !ALGORITHMS.contains(&header.alg) {
+            return Err(Error::Unauthorized(format!(
+                "tokens signed with {:?} are not accepted",
+                header.alg
+            )));
+        }
+        let kid = header
+            .kid
+            .ok_or_else(|| Error::Unauthorized("token names no key id".to_owned()))?;
+
+        let key = match self.key(&kid)? {
+            Some(key) => key,
+            None => {
+                self.reload().await;
+                self.key(&kid)?.ok_or_else(|| {
+                    Error::Unauthorized(format!("token signed with unknown key {:?}", kid))
+                })?
+            }
+        };
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&[&self.audience]);
+        validation.required_spec_claims = ["exp", "iss", "aud", "sub"]
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+
+        jsonwebtoken::decode::<Claims>(token, &key, &validation)
+            .map(|data| data.claims)
+            .map_err(|error| Error::Unauthorized(format!("invalid token: {}", error)))
+    }
+
+    fn key(&self, kid: &str) -> Result<Option<DecodingKey>> {
+        let keys = self.keys.read().unwrap_or_else(PoisonError::into_inner);
+        keys.set
+            .find(kid)
+            .map(|jwk| {
+                DecodingKey::from_jwk(jwk)
+                    .with_context(|| format!("JWKS key {:?} is unusable", kid))
+                    .map_err(Error::from)
+            })
+            .transpose()
+    }
+
+    /// Reloads the key set, unless it was loaded recently. A failed reload
+    /// keeps the current keys.
+    async fn reload(&self) {
+        let _reloading = self.reloading.lock().await;
+        let loaded_at = self
+            .keys
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .loaded_at;
+        if loaded_at.elapsed() < MIN_RELOAD_INTERVAL {
+            return;
+        }
+
+        tracing::info!("reloading JWKS from {:?}", self.source);
+        let set = fetch(&self.source, &self.client).await;
+        let mut keys = self.keys.write().unwrap_or_else(PoisonError::into_inner);
+        keys.loaded_at = Instant::now();
+        match set {
+            Ok(set) => keys.set = set,
+            Err(error) => tracing::warn!("failed to reload JWKS: {:#}", error),
+        }
+    }
+}
+
+async fn fetch(source: &Source, client: &reqwest::Client) -> anyhow::Result<JwkSet> {
+    match source {
+        Source::File(path) => {
+            let content = tokio::fs::read_to_string(path)
+                .await
+                .with_context(|| format!("failed to read JWKS file {}", path.display()))?;
+            serde_json::from_str(&content)
+                .with_context(|| format!("failed to parse JWKS file {}", path.display()))
+        }
+        Source::Url(url) => async {
+            client
+                .get(url)
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<JwkSet>()
+                .await
+        }
+        .await
+        .with_context(|| format!("failed to fetch JWKS from {}", url)),
+    }
+}
@@ -0,0 +1,425 @@
+use super::{hash_token, Claims, JwtVerifier, KeyStore, Principal, Scope};
+use crate::{rate_limit::RateLimitLayer, Error, Result};
+use axum::http::{header, HeaderMap, Method, Request};
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tower::{BoxError, Layer, Service};
+
+/// Paths anyone may read, even when reads need a key. Load balancers probe
+/// the health checks without credentials.
+const PUBLIC_PATHS: &[&str] = &["/", "/healthz", "/readyz"];
+
+/// Authenticates requests carrying `Authorization: Bearer <key>` and checks
+/// that the key grants the scope the request needs. With a `JwtVerifier`,
+/// JWTs are accepted too, granting the scopes of their `scope` claim:
+///
+/// - `admin` for everything under `/admin`,
+/// - `write` for any other request that is not a `GET`, `HEAD` or `OPTIONS`,
+/// - `read` for the rest, only if reads are restricted.
+///
+/// Unless enforced, only `/admin` needs a key, so keys can be issued before
+/// turning authentication on.
+///
+/// Requests failing the check are rejected with `Error::Unauthorized` or
+/// `Error::Forbidden` as the service error, for `handle_error` to render. The
+/// `Principal` of accepted requests is added to their extensions, with the
+/// `Claims` of their JWT if they sent one. With a `RateLimitLayer`, failed
+/// authentications are limited per IP address, and credentials from an
+/// address past its limit are rejected without being looked up.
+#[derive(Clone)]
+pub struct AuthLayer {
+    keys: Arc<dyn KeyStore>,
+    jwt: Option<Arc<JwtVerifier>>,
+    bootstrap_hash: Option<String>,
+    rate_limit: Option<RateLimitLayer>,
+    enforce: bool,
+    require_read: bool,
+}
+
+impl AuthLayer {
+    pub fn new(keys: Arc<dyn KeyStore>) -> Self {
+        Self {
+            keys,
+            jwt: None,
+            bootstrap_hash: None,
+            rate_limit: None,
+            enforce: true,
+            require_read: false,
+        }
+    }
+
+    /// Accepts `token` as an admin key that is not in the key store, to issue
+    /// the first keys with.
+    pub fn bootstrap_key(mut self, token: &str) -> Self {
+        self.bootstrap_hash = Some(hash_token(token));
+        self
+    }
+
+    /// Accepts JWTs that `verifier` validates.
+    pub fn jwt(mut self, verifier: Arc<JwtVerifier>) -> Self {
+        self.jwt = Some(verifier);
+        self
+    }
+
+    /// Charges failed authentications to `rate_limit`.
+    pub fn rate_limit(mut self, rate_limit: RateLimitLayer) -> Self {
+        self.rate_limit = Some(rate_limit);
+        self
+    }
+
+    /// Whether requests outside `/admin` need a key.
+    pub fn enforce(mut self, enforce: bool) -> Self {
+        self.enforce = enforce;
+        self
+    }
+
+    /// Requires the `read` scope for reads too.
+    pub fn require_read(mut self, require_read: bool) -> Self {
+        self.require_read = require_read;
+        self
+    }
+
+    fn required_scope(&self, method: &Method, path: &str) -> Option<Scope> {
+        if path == "/admin" || path.starts_with("/admin/") {
+            Some(Scope::Admin)
+        } else if !self.enforce {
+            None
+        } else if !matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS) {
+            Some(Scope::Write)
+        } else if self.require_read && !PUBLIC_PATHS.contains(&path) {
+            Some(Scope::Read)
+        } else {
+            None
+        }
+    }
+
+    /// Returns who sent the request, with the claims of their JWT if they sent
+    /// one, or `None` if it carries no credentials.
+    async fn authenticate(
+        &self,
+        headers: &HeaderMap,
+    ) -> Result<Option<(Principal, Option<Claims>)>> {
+        let value = match headers.get(header::AUTHORIZATION) {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+
+        let token = value
+            .to_str()
+            .ok()
+            .and_then(|value| value.split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+            .map(|(_, token)| token.trim())
+            .filter(|token| !token.is_empty())
+            .ok_or_else(|| {
+                Error::Unauthorized("expected an Authorization: Bearer header".to_owned())
+            })?;
+
+        // API keys are hex, so only JWTs have three dot-separated parts.
+        if let Some(jwt) = &self.jwt {
+            if token.matches('.').count() == 2 {
+                let claims = jwt.verify(token).await?;
+                return Ok(Some((claims.principal(), Some(claims))));
+            }
+        }
+
+        let hash = hash_token(token);
+        if self.bootstrap_hash.as_ref() == Some(&hash) {
+            let principal = Principal {
+                id: "bootstrap".to_owned(),
+                name: "bootstrap".to_owned(),
+                scopes: vec![Scope::Admin],
+            };
+            return Ok(Some((principal, None)));
+        }
+
+        match self.keys.find_key(&hash).await? {
+            Some(key) => {
+                let principal = Principal {
+                    id: key.id,
+                    name: key.name,
+                    scopes: key.scopes,
+                };
+                Ok(Some((principal, None)))
+            }
+            None => Err(Error::Unauthorized(
+                "API key is invalid or revoked".to_owned(),
+            )),
+        }
+    }
+
+    async fn authorize<B>(&self, req: &mut Request<B>) -> Result<()> {
+        let rate_limit = self
+            .rate_limit
+            .as_ref()
+            .filter(|_| req.headers().contains_key(header::AUTHORIZATION));
+        if let Some(rate_limit) = rate_limit {
+            rate_limit.check_auth_failures(req)?;
+        }
+
+        let (principal, claims) = match self.authenticate(req.headers()).await {
+            Ok(Some((principal, claims))) => (Some(principal), claims),
+            Ok(None) => (None, None),
+            Err(error @ Error::Unauthorized(_)) => {
+                if let Some(rate_limit) = rate_limit {
+                    rate_limit.charge_auth_failure(req);
+                }
+                return Err(error);
+            }
+            Err(error) => return Err(error),
+        };
+
+        if let Some(scope) = self.required_scope(req.method(), req.uri().path()) {
+            match &principal {
+                None => {
+                    return Err(Error::Unauthorized(format!(
+                        "an API key or token with the {} scope is required",
+                        scope
+                    )))
+                }
+                Some(principal) if !principal.allows(scope) => {
+                    return Err(Error::Forbidden(format!(
+                        "{} {} lacks the {} scope",
+                        if claims.is_some() {
+                            "token for"
+                        } else {
+                            "API key"
+                        },
+                        principal.id,
+                        scope
+                    )))
+                }
+                Some(_) => {}
+            }
+        }
+
+        if let Some(principal) = principal {
+            req.extensions_mut().insert(principal);
+        }
+        if let Some(claims) = claims {
+            req.extensions_mut().insert(claims);
+        }
+
+        Ok(())
+    }
+}
+
+impl<S> Layer<S> for AuthLayer {
+    type Service = AuthService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        AuthService {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct AuthService<S> {
+    inner: S,
+    layer: AuthLayer,
+}
+
+impl<S, ReqBody> Service<Request<ReqBody>> for AuthService<S>
+where
+    S: Service<Request<ReqBody>> + Clone + Send + 'static,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+    ReqBody: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
+        // Call the service that was polled ready, leaving a fresh clone behind.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let layer = self.layer.clone();
+
+        Box::pin(async move {
+            layer.authorize(&mut req).await?;
+            inner.call(req).await.map_err(Into::into)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth::{ApiKey, MemoryKeyStore},
+        rate_limit::Limit,
+    };
+    use axum::http::HeaderValue;
+
+    const BOOTSTRAP: &str = "adv_bootstrap";
+
+    fn layer() -> AuthLayer {
+        AuthLayer::new(Arc::new(MemoryKeyStore::new())).bootstrap_key(BOOTSTRAP)
+    }
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {}", token)).unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn admin_paths_always_need_the_admin_scope() {
+        for layer in [layer(), layer().enforce(false), layer().require_read(true)] {
+            for path in ["/admin", "/admin/keys", "/admin/keys/1"] {
+                assert_eq!(layer.required_scope(&Method::GET, path), Some(Scope::Admin));
+                assert_eq!(
+                    layer.required_scope(&Method::POST, path),
+                    Some(Scope::Admin)
+                );
+            }
+            assert_ne!(
+                layer.required_scope(&Method::GET, "/administer"),
+                Some(Scope::Admin)
+            );
+        }
+    }
+
+    #[test]
+    fn writes_need_the_write_scope_when_enforced() {
+        let layer = layer();
+        for method in [Method::POST, Method::PUT, Method::PATCH, Method::DELETE] {
+            assert_eq!(
+                layer.required_scope(&method, "/advices"),
+                Some(Scope::Write)
+            );
+            assert_eq!(layer.required_scope(&method, "/"), Some(Scope::Write));
+        }
+        for method in [Method::GET, Method::HEAD, Method::OPTIONS] {
+            assert_eq!(layer.required_scope(&method, "/advices"), None);
+        }
+
+        let layer = layer.enforce(false).require_read(true);
+        assert_eq!(layer.required_scope(&Method::POST, "/advices"), None);
+        assert_eq!(layer.required_scope(&Method::GET, "/advices"), None);
+    }
+
+    #[test]
+    fn reads_need_the_read_scope_outside_public_paths_when_required() {
+        let layer = layer().require_read(true);
+        for method in [Method::GET, Method::HEAD, Method::OPTIONS] {
+            assert_eq!(layer.required_scope(&method, "/advices"), Some(Scope::Read));
+            assert_eq!(
+                layer.required_scope(&method, "/advices/1"),
+                Some(Scope::Read)
+            );
+            for path in PUBLIC_PATHS {
+                assert_eq!(layer.required_scope(&method, path), None);
+            }
+        }
+        assert_eq!(
+            layer.required_scope(&Method::POST, "/healthz"),
+            Some(Scope::Write)
+        );
+    }
+
+    #[tokio::test]
+    async fn requests_without_credentials_are_anonymous() {
+        assert!(layer()
+            .authenticate(&HeaderMap::new())
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn malformed_credentials_are_rejected() {
+        let layer = layer();
+        let mut headers = HeaderMap::new();
+        for value in ["Basic YWRtaW4=", "Bearer", "Bearer  ", BOOTSTRAP] {
+            headers.insert(header::AUTHORIZATION, HeaderValue::from_static(value));
+            assert!(matches!(
+                layer.authenticate(&headers).await,
+                Err(Error::Unauthorized(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn keys_are_found_by_hash_until_revoked() {
+        let keys = Arc::new(MemoryKeyStore::new());
+        let (key, token) = ApiKey::generate("ci", vec![Scope::Write]);
+        let id = key.id.clone();
+        keys.insert_key(key).await.unwrap();
+        let layer = AuthLayer::new(keys.clone()).bootstrap_key(BOOTSTRAP);
+
+        let (principal, claims) = layer.authenticate(&bearer(&token)).await.unwrap().unwrap();
+        assert_eq!(principal.id, id);
+        assert_eq!(principal.scopes, vec![Scope::Write]);
+        assert!(claims.is_none());
+
+        let (principal, _) = layer
+            .authenticate(&bearer(BOOTSTRAP))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(principal.allows(Scope::Admin));
+
+        keys.revoke_key(&id).await.unwrap();
+        assert!(matches!(
+            layer.authenticate(&bearer(&token)).await,
+            Err(Error::Unauthorized(_))
+        ));
+        assert!(matches!(
+            layer.authenticate(&bearer("adv_guessed")).await,
+            Err(Error::Unauthorized(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn failed_authentications_are_limited() {
+        let limit = Limit {
+            burst: 100,
+            per_minute: 100,
+        };
+        let failures = Limit {
+            burst: 2,
+            per_minute: 1,
+        };
+        let layer = layer().rate_limit(RateLimitLayer::new(limit, limit, failures));
+        let request = |token: Option<&str>| {
+            let mut req = Request::post("/advices").body(()).unwrap();
+            if let Some(token) = token {
+                *req.headers_mut() = bearer(token);
+            }
+            req
+        };
+
+        for _ in 0..2 {
+            assert!(matches!(
+                layer.authorize(&mut request(Some("adv_guessed"))).await,
+                Err(Error::Unauthorized(_))
+            ));
+        }
+        for token in ["adv_guessed", BOOTSTRAP] {
+            assert!(matches!(
+                layer.authorize(&mut request(Some(token))).await,
+                Err(Error::RateLimited(..))
+            ));
+        }
+        // Anonymous requests are no failed authentications.
+        assert!(matches!(
+            layer.authorize(&mut request(None)).await,
+            Err(Error::Unauthorized(_))
+        ));
+    }
+}
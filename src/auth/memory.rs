@@ -0,0 +1,54 @@
+use super::{ApiKey, KeyStore};
+use crate::Result;
+use async_trait::async_trait;
+use chrono::Utc;
+use std::{collections::HashMap, sync::RwLock};
+
+/// Keeps API keys in memory; they are lost on restart.
+#[derive(Debug, Default)]
+pub struct MemoryKeyStore {
+    keys: RwLock<HashMap<String, ApiKey>>,
+}
+
+impl MemoryKeyStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl KeyStore for MemoryKeyStore {
+    async fn insert_key(&self, key: ApiKey) -> Result<()> {
+        let mut keys = self.keys.write()?;
+        keys.insert(key.id.clone(), key);
+        Ok(())
+    }
+
+    async fn find_key(&self, hash: &str) -> Result<Option<ApiKey>> {
+        let keys = self.keys.read()?;
+        let key = keys
+            .values()
+            .find(|key| key.hash == hash && key.revoked_at.is_none());
+
+        Ok(key.cloned())
+    }
+
+    async fn keys(&self) -> Result<Vec<ApiKey>> {
+        let keys = self.keys.read()?;
+        let mut keys = keys.values().cloned().collect::<Vec<_>>();
+        keys.sort_by(|a, b| a.created_at.cmp(&b.created_at).then(a.id.cmp(&b.id)));
+
+        Ok(keys)
+    }
+
+    async fn revoke_key(&self, id: &str) -> Result<bool> {
+        let mut keys = self.keys.write()?;
+        match keys.get_mut(id) {
+            Some(key) if key.revoked_at.is_none() => {
+                key.revoked_at = Some(Utc::now());
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
@@ -0,0 +1,143 @@
+//! Authentication with API keys or JWTs sent as bearer tokens.
+//!
+//! Keys are random tokens shown once when issued; only their SHA-256 hash is
+//! stored. JWTs come from an identity provider and are checked against its
+//! key set. Both carry scopes, and `AuthLayer` checks that the scope a request
+//! needs is granted before it reaches a handler.
+
+use crate::{Error, Result};
+use async_trait::async_trait;
+use axum::extract::{FromRequest, RequestParts};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{fmt, str::FromStr};
+
+mod jwt;
+mod layer;
+mod memory;
+
+pub use jwt::{Claims, JwtVerifier};
+pub use layer::{AuthLayer, AuthService};
+pub use memory::MemoryKeyStore;
+
+/// Prefix of issued keys, so they are easy to recognise in logs and secret
+/// scanners.
+const KEY_PREFIX: &str = "adv_";
+
+/// What a key may do. Each scope includes the ones before it: `write` can
+/// also read, and `admin` can do everything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Read,
+    Write,
+    Admin,
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Scope::Read => "read",
+            Scope::Write => "write",
+            Scope::Admin => "admin",
+        })
+    }
+}
+
+impl FromStr for Scope {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "read" => Ok(Scope::Read),
+            "write" => Ok(Scope::Write),
+            "admin" => Ok(Scope::Admin),
+            _ => Err(Error::BadRequest(format!(
+                "unknown scope {:?}, expected read, write or admin",
+                s
+            ))),
+        }
+    }
+}
+
+/// An issued API key, without the key itself.
+#[derive(Debug, Clone, Serialize)]
+pub struct ApiKey {
+    /// Public identifier, used to revoke the key.
+    pub id: String,
+    /// What the key is for, chosen when issuing it.
+    pub name: String,
+    #[serde(skip)]
+    pub hash: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+impl ApiKey {
+    /// Generates a key and returns it with the token to hand to the client.
+    pub fn generate(name: impl Into<String>, scopes: Vec<Scope>) -> (Self, String) {
+        let token = format!("{}{}", KEY_PREFIX, hex::encode(rand::random::<[u8; 32]>()));
+        let key = Self {
+            id: hex::encode(rand::random::<[u8; 8]>()),
+            name: name.into(),
+            hash: hash_token(&token),
+            scopes,
+            created_at: Utc::now(),
+            revoked_at: None,
+        };
+
+        (key, token)
+    }
+}
+
+/// Who is making a request, as established by `AuthLayer`. Handlers can take
+/// it as an extractor, which fails with `Error::Unauthorized` for anonymous
+/// requests.
+#[derive(Debug, Clone)]
+pub struct Principal {
+    /// Id of the key used, or subject of the token.
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+}
+
+impl Principal {
+    /// Whether `scope` is granted, directly or through a broader scope.
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.iter().any(|granted| *granted >= scope)
+    }
+}
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for Principal {
+    type Rejection = Error;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self> {
+        req.extensions()
+            .and_then(|extensions| extensions.get::<Principal>())
+            .cloned()
+            .ok_or_else(|| Error::Unauthorized("credentials are required".to_owned()))
+    }
+}
+
+/// Storage of issued API keys.
+#[async_trait]
+pub trait KeyStore: Send + Sync {
+    async fn insert_key(&self, key: ApiKey) -> Result<()>;
+
+    /// Returns the key with the given hash unless it has been revoked.
+    async fn find_key(&self, hash: &str) -> Result<Option<ApiKey>>;
+
+    /// Returns every issued key, revoked ones included, oldest first.
+    async fn keys(&self) -> Result<Vec<ApiKey>>;
+
+    /// Returns `false` if there is no such key or it was already revoked.
+    async fn revoke_key(&self, id: &str) -> Result<bool>;
+}
+
+/// Hex-encoded SHA-256 of a token, as stored in place of the token.
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
@@ -1,106 +1,1320 @@
+use advices_api::{
+    auth::{ApiKey, AuthLayer, JwtVerifier, KeyStore, MemoryKeyStore, Principal, Scope},
+    config::Config,
+    dedup::DuplicateIndex,
+    etag,
+    health::Health,
+    metrics::{Metrics, MetricsLayer},
+    pick,
+    provider::{
+        AdviceProvider, AdviceSlip, BatchParams, Corpus, Fallback, FallbackAdvice, UpstreamClient,
+    },
+    rate_limit::RateLimitLayer,
+    request_id::RequestIdLayer,
+    search::{self, Highlight, SearchIndex},
+    store::{AdviceStore, Cursor, IndexedStore, ListQuery, MemoryStore, SqliteStore},
+    transfer::{Decoder, Encoder, Format},
+    Advice, Error, Result,
+};
+use anyhow::Context;
 use axum::{
-    extract::{Extension, Path},
-    handler::{delete, get},
-    http::StatusCode,
+    body::{box_body, Body, BoxBody, Bytes},
+    extract::{
+        rejection::{BytesRejection, JsonRejection, PathParamsRejection, QueryRejection},
+        BodyStream, Extension, Path, Query,
+    },
+    handler::{delete, get, post},
+    http::{header, HeaderMap, HeaderValue, Response, StatusCode},
     response::IntoResponse,
     Json, Router,
 };
-use serde::{Deserialize, Serialize};
+use chrono::{NaiveDate, Utc};
+use chrono_tz::Tz;
+use futures::stream::{self, StreamExt};
+use rand::Rng;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    collections::HashSet,
     convert::Infallible,
     net::SocketAddr,
-    sync::{Arc, RwLock},
-    time::Duration,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 use tower::{BoxError, ServiceBuilder};
 use tower_http::{add_extension::AddExtensionLayer, trace::TraceLayer};
 
 #[tokio::main]
 async fn main() {
+    let config = Config::load().unwrap_or_else(|error| {
+        eprintln!("invalid configuration: {:#}", error);
+        std::process::exit(1);
+    });
+
     if std::env::var_os("RUST_LOG").is_none() {
-        std::env::set_var("RUST_LOG", "tower_http=debug")
+        std::env::set_var("RUST_LOG", &config.log)
     }
     tracing_subscriber::fmt::init();
 
-    let db = Db::default();
+    if let Err(error) = run(config).await {
+        eprintln!("failed to run server: {:#}", error);
+        std::process::exit(1);
+    }
+}
+
+/// Sets the server up as `config` says and serves requests until it fails.
+async fn run(config: Config) -> anyhow::Result<()> {
+    // Persist advices and API keys to SQLite when a database file is
+    // configured, otherwise keep them in memory.
+    let (store, keys): (Store, Keys) = match &config.storage.database {
+        Some(path) => {
+            let sqlite = Arc::new(SqliteStore::open(path)?);
+            (sqlite.clone(), sqlite)
+        }
+        None => (
+            Arc::new(MemoryStore::new()),
+            Arc::new(MemoryKeyStore::new()),
+        ),
+    };
+
+    // Index stored advices for full-text search and duplicate detection, and
+    // keep the indexes in sync with every write from here on.
+    let search_index = Arc::new(SearchIndex::new());
+    let duplicates = Arc::new(DuplicateIndex::new());
+    let store: Store = Arc::new(
+        IndexedStore::new(store, search_index.clone(), duplicates.clone())
+            .await
+            .context("failed to index stored advices")?,
+    );
+
+    // Serve advices from a local corpus when one is configured, so the server
+    // can run without network access.
+    let corpus = config
+        .upstream
+        .corpus
+        .as_ref()
+        .map(Corpus::load)
+        .transpose()?;
+    let provider: Provider = match &corpus {
+        Some(corpus) => Arc::new(corpus.clone()),
+        None => {
+            let client = UpstreamClient::new(&config.upstream)
+                .context("failed to set up the upstream client")?;
+            Arc::new(
+                AdviceSlip::new(&config.upstream.url, client).cache(config.upstream.cache_ttl()),
+            )
+        }
+    };
+    tracing::debug!("using {} advice provider", provider.name());
+
+    // Stand in for a slow or failing provider with the configured corpus, or
+    // the bundled one.
+    let fallback = config.upstream.fallback.then(|| {
+        Arc::new(Fallback::new(
+            corpus.unwrap_or_else(Corpus::bundled),
+            config.upstream.fallback_deadline(),
+        ))
+    });
+
+    let metrics = Arc::new(Metrics::new().context("failed to register metrics")?);
+
+    let mut health = Health::new(store.clone());
+    if config.health.check_upstream {
+        health = health.check_upstream(
+            provider.clone(),
+            config.health.upstream_ttl(),
+            config.upstream.longest_call(),
+        );
+    }
+    let health = Arc::new(health);
+
+    // Keyed by the principal `auth` sets, so layered inside it. `auth` charges
+    // failed authentications to it too, as those never get that far.
+    let rate_limit = RateLimitLayer::new(
+        config.rate_limit.read(),
+        config.rate_limit.upstream(),
+        config.rate_limit.auth_failure(),
+    )
+    .enforce(config.rate_limit.enabled)
+    .trust_forwarded_for(config.rate_limit.trust_forwarded_for);
+    // Larger batches could never go through the upstream rate limit.
+    let max_batch_count = if config.rate_limit.enabled {
+        MAX_BATCH_COUNT.min(config.rate_limit.upstream_burst as usize)
+    } else {
+        MAX_BATCH_COUNT
+    };
+
+    let mut auth = AuthLayer::new(keys.clone())
+        .rate_limit(rate_limit.clone())
+        .enforce(config.auth.enabled)
+        .require_read(config.auth.require_read);
+    if let Some(key) = &config.auth.bootstrap_key {
+        auth = auth.bootstrap_key(key);
+    }
+    if let (Some(jwks), Some(issuer), Some(audience)) = (
+        &config.auth.jwks,
+        &config.auth.jwt_issuer,
+        &config.auth.jwt_audience,
+    ) {
+        let verifier = JwtVerifier::load(jwks, issuer, audience).await?;
+        auth = auth.jwt(Arc::new(verifier));
+    }
+    if !config.auth.enabled {
+        tracing::warn!("authentication is disabled, anyone can change advices");
+    } else if config.auth.bootstrap_key.is_none()
+        && config.auth.jwks.is_none()
+        && !keys
+            .keys()
+            .await?
+            .iter()
+            .any(|key| key.revoked_at.is_none())
+    {
+        anyhow::bail!(
+            "authentication is enabled but no one could authenticate: set \
+             ADVICES_AUTH_BOOTSTRAP_KEY to issue the first API keys with, configure a JWKS, \
+             or disable authentication"
+        );
+    }
 
     // Compose the routes
     let app = Router::new()
         .route("/", get(root))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/metrics", get(metrics_show))
         .route("/advices", get(advices_index).post(advices_create))
-        .route("/advices/:id", delete(advices_delete))
+        .route(
+            "/advices/:id",
+            get(advices_show)
+                .put(advices_replace)
+                .patch(advices_patch)
+                .delete(advices_delete),
+        )
+        // Added after `/advices/:id` so that it takes precedence
+        .route("/advices/random", get(advices_random))
+        .route("/advices/daily", get(advices_daily))
+        .route("/advices/search", get(advices_search))
+        .route("/advices/duplicates", get(advices_duplicates))
+        .route("/advices/batch", post(advices_batch))
+        .route("/advices/import", post(advices_import))
+        .route("/advices/import-upstream", post(advices_import_upstream))
+        .route("/advices/export", get(advices_export))
+        .route("/advices/:id/tags", post(advice_tags_attach))
+        .route("/advices/:id/tags/:tag", delete(advice_tags_detach))
+        .route("/tags", get(tags_index).post(tags_create))
+        .route("/tags/:name", delete(tags_delete))
+        .route("/admin/keys", get(keys_index).post(keys_create))
+        .route("/admin/keys/:id", delete(keys_delete))
         // Add middleware to all routes
         .layer(
             ServiceBuilder::new()
-                .timeout(Duration::from_secs(10))
+                .timeout(config.server.request_timeout())
                 .layer(TraceLayer::new_for_http())
-                .layer(AddExtensionLayer::new(db))
+                .layer(auth)
+                .layer(rate_limit)
+                .layer(AddExtensionLayer::new(store))
+                .layer(AddExtensionLayer::new(keys))
+                .layer(AddExtensionLayer::new(provider))
+                .layer(AddExtensionLayer::new(fallback))
+                .layer(AddExtensionLayer::new(search_index))
+                .layer(AddExtensionLayer::new(duplicates))
+                .layer(AddExtensionLayer::new(metrics.clone()))
+                .layer(AddExtensionLayer::new(health))
+                .layer(AddExtensionLayer::new(RequestTimeout(
+                    config.server.request_timeout(),
+                )))
+                .layer(AddExtensionLayer::new(MaxBatchCount(max_batch_count)))
                 .into_inner(),
         )
-        .handle_error(|error: BoxError| {
-            let result = if error.is::<tower::timeout::error::Elapsed>() {
-                Ok(StatusCode::REQUEST_TIMEOUT)
-            } else {
-                Err((
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    format!("Unhandled internal error: {}", error),
-                ))
-            };
+        .handle_error({
+            let metrics = metrics.clone();
+            move |error: BoxError| {
+                let error = if error.is::<tower::timeout::error::Elapsed>() {
+                    metrics.inc_timeouts();
+                    Error::Timeout
+                } else {
+                    // Middleware such as `AuthLayer` and `RateLimitLayer`
+                    // rejects requests with our own errors.
+                    match error.downcast::<Error>() {
+                        Ok(error) => *error,
+                        Err(error) => {
+                            Error::Internal(anyhow::anyhow!("Unhandled internal error: {}", error))
+                        }
+                    }
+                };
 
-            Ok::<_, Infallible>(result)
+                Ok::<_, Infallible>(error)
+            }
         })
+        // Outside `handle_error`, to count requests rejected by middleware
+        .layer(MetricsLayer::new(metrics))
+        // Outermost, so that every error response carries the request id
+        .layer(RequestIdLayer)
         // Make sure all errors have been handled
         .check_infallible();
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
+    let addr = config.server.addr();
     tracing::debug!("listening on {}", addr);
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+    // `bind` would panic when the address is taken
+    axum::Server::try_bind(&addr)
+        .with_context(|| format!("failed to bind {}", addr))?
+        // Rate limiting needs the client address
+        .serve(app.into_make_service_with_connect_info::<SocketAddr, _>())
         .await
-        .unwrap();
+        .context("server failed")?;
+
+    Ok(())
 }
 
 async fn root() -> &'static str {
     env!("CARGO_PKG_VERSION")
 }
 
-async fn advices_index(Extension(db): Extension<Db>) -> impl IntoResponse {
-    let advices = db.read().unwrap();
-    let advices = advices.values().cloned().collect::<Vec<_>>();
+#[derive(Debug, Serialize)]
+struct Liveness {
+    status: &'static str,
+    version: &'static str,
+}
+
+/// Answers as long as the process can handle requests at all.
+async fn healthz() -> impl IntoResponse {
+    Json(Liveness {
+        status: "ok",
+        version: env!("CARGO_PKG_VERSION"),
+    })
+}
+
+/// Checks the dependencies, answering 503 when a critical one fails so that
+/// the load balancer stops routing traffic here.
+async fn readyz(Extension(health): Extension<Arc<Health>>) -> impl IntoResponse {
+    let readiness = health.readiness().await;
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
 
-    Json(advices)
+    (status, Json(readiness))
 }
 
-async fn advices_create(Extension(db): Extension<Db>) -> impl IntoResponse {
-    let advice = advices_generate().await.unwrap();
-    db.write().unwrap().insert(advice.id, advice.clone());
+/// Renders the metrics in the Prometheus text format, counting the stored
+/// advices first.
+async fn metrics_show(
+    Extension(store): Extension<Store>,
+    Extension(metrics): Extension<Arc<Metrics>>,
+) -> Result<impl IntoResponse> {
+    metrics.set_stored_advices(store.count(None).await?);
+    let (text, content_type) = metrics.render().map_err(anyhow::Error::from)?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_str(&content_type).map_err(anyhow::Error::from)?,
+    );
+
+    Ok((headers, text))
+}
 
-    (StatusCode::CREATED, Json(advice))
+#[derive(Debug, Deserialize)]
+struct IndexParams {
+    limit: Option<usize>,
+    cursor: Option<String>,
+    sort: Option<String>,
+    q: Option<String>,
+    tag: Option<String>,
 }
 
-async fn advices_delete(Path(id): Path<i64>, Extension(db): Extension<Db>) -> impl IntoResponse {
-    if db.write().unwrap().remove(&id).is_some() {
-        StatusCode::NO_CONTENT
+impl IndexParams {
+    fn into_query(self) -> Result<ListQuery> {
+        let limit = self.limit.unwrap_or(ListQuery::DEFAULT_LIMIT);
+        if limit == 0 || limit > ListQuery::MAX_LIMIT {
+            return Err(Error::BadRequest(format!(
+                "limit must be between 1 and {}",
+                ListQuery::MAX_LIMIT
+            )));
+        }
+
+        Ok(ListQuery {
+            limit,
+            sort: self.sort.as_deref().unwrap_or("id").parse()?,
+            after: self.cursor.as_deref().map(str::parse).transpose()?,
+            q: self.q.filter(|q| !q.is_empty()),
+            tag: self.tag.as_deref().map(Advice::validate_tag).transpose()?,
+        })
+    }
+}
+
+async fn advices_index(
+    query: Result<Query<IndexParams>, QueryRejection>,
+    Extension(store): Extension<Store>,
+) -> Result<impl IntoResponse> {
+    let Query(params) = query.map_err(bad_request)?;
+    let page = store.list(&params.into_query()?).await?;
+
+    Ok(Json(page))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NewAdvice {
+    advice: String,
+    #[serde(default)]
+    tags: Vec<String>,
+    author: Option<String>,
+}
+
+/// Stores the advice from the JSON body if there is one, otherwise fetches a
+/// random one from the provider. Either way, new advices record who created
+/// them.
+async fn advices_create(
+    body: Result<Bytes, BytesRejection>,
+    Extension(store): Extension<Store>,
+    Extension(provider): Extension<Provider>,
+    Extension(fallback): Extension<Option<Arc<Fallback>>>,
+    Extension(metrics): Extension<Arc<Metrics>>,
+    principal: Option<Principal>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse> {
+    let body = body.map_err(bad_request)?;
+    let created_by = principal.map(|principal| principal.id);
+    // Only an empty body asks for an upstream advice; any other body is
+    // meant as the advice, and is rejected unless it is JSON.
+    let (status, advice) = if !body.is_empty() {
+        let new = json_body::<NewAdvice>(&headers, &body)?;
+        let text = Advice::validate_text(&new.advice)?;
+        let mut advice = Advice::new(0, text, Advice::SOURCE_USER);
+        advice.tags = Advice::validate_tags(&new.tags)?;
+        if let Some(author) = &new.author {
+            advice.author = Advice::validate_author(author)?;
+        }
+        advice.created_by = created_by;
+        (StatusCode::CREATED, store.insert_local(advice).await?)
+    } else if let Some(fallback) = fallback {
+        return advices_generate(&store, &provider, &fallback, &metrics, created_by).await;
+    } else {
+        let advice = fetch_random(&provider, &metrics, None).await?;
+        store_fetched(&store, advice, created_by).await?
+    };
+
+    Ok((status, HeaderMap::new(), Json(advice)))
+}
+
+/// Stores an advice fetched from the provider on behalf of `created_by`,
+/// unless it is stored already. Users may have tagged or edited the stored
+/// one since, so it is returned as it is, with a 200 rather than a 201.
+async fn store_fetched(
+    store: &Store,
+    mut advice: Advice,
+    created_by: Option<String>,
+) -> Result<(StatusCode, Advice)> {
+    if let Some(stored) = store.get(advice.id).await? {
+        return Ok((StatusCode::OK, stored));
+    }
+    advice.created_by = created_by;
+    store.insert(advice.clone()).await?;
+
+    Ok((StatusCode::CREATED, advice))
+}
+
+/// Sent with fallback advices.
+const FALLBACK_WARNING: &str = "199 - \"upstream unavailable, serving a fallback advice\"";
+
+/// Fetches a random advice from the provider and stores it if it is new,
+/// giving the provider until the fallback deadline. Past that, or if it
+/// fails, serves a fallback advice marked with a `Warning` header.
+async fn advices_generate(
+    store: &Store,
+    provider: &Provider,
+    fallback: &Fallback,
+    metrics: &Metrics,
+    created_by: Option<String>,
+) -> Result<(StatusCode, HeaderMap, Json<Advice>)> {
+    let error = match fetch_random(provider, metrics, Some(fallback.deadline())).await {
+        Ok(advice) => {
+            let (status, advice) = store_fetched(store, advice, created_by).await?;
+            return Ok((status, HeaderMap::new(), Json(advice)));
+        }
+        Err(error) => error,
+    };
+    tracing::warn!("serving a fallback advice: {:#}", error);
+
+    let FallbackAdvice { advice, created } = fallback.advice(store.as_ref(), created_by).await?;
+    let status = if created {
+        StatusCode::CREATED
     } else {
-        StatusCode::NOT_FOUND
+        StatusCode::OK
+    };
+    let mut headers = HeaderMap::new();
+    headers.insert(header::WARNING, HeaderValue::from_static(FALLBACK_WARNING));
+
+    Ok((status, headers, Json(advice)))
+}
+
+/// Fetches a random advice from the provider, failing with `Error::Timeout`
+/// past `deadline`, and records the call in the metrics.
+async fn fetch_random(
+    provider: &Provider,
+    metrics: &Metrics,
+    deadline: Option<Duration>,
+) -> Result<Advice> {
+    let started = Instant::now();
+    let result = match deadline {
+        Some(deadline) => provider.random_within(deadline).await,
+        None => provider.random().await,
+    };
+    metrics.observe_upstream(provider.name(), started.elapsed(), result.as_ref().err());
+
+    result
+}
+
+#[derive(Debug, Default, Serialize)]
+struct BatchReport {
+    /// Advices that were not stored before.
+    inserted: usize,
+    /// Advices already stored, which are left as they are.
+    known: usize,
+    /// Provider calls returning an advice fetched earlier in the batch.
+    duplicates: usize,
+    /// Advices not stored because their text duplicates a stored advice.
+    similar: usize,
+    /// Provider calls that failed.
+    failed: usize,
+}
+
+/// Most advices a batch may request. Lower with rate limiting, which caps
+/// batches at the upstream burst; see `MaxBatchCount`.
+const MAX_BATCH_COUNT: usize = 50;
+/// Provider calls in flight at once.
+const BATCH_CONCURRENCY: usize = 4;
+/// Provider calls allowed per requested advice, duplicates and failures
+/// included.
+const BATCH_ATTEMPTS_PER_ADVICE: usize = 3;
+/// Pause before calling again for advices that came back as duplicates or
+/// failed. adviceslip serves the same slip for 2 seconds, so calling again
+/// any sooner would only get more duplicates.
+const BATCH_RETRY_DELAY: Duration = Duration::from_millis(2_100);
+
+/// The server's request timeout. Handlers doing long work in steps, such as
+/// batches and imports, stop short of it and report what they did, rather
+/// than being cut off with nothing to show for it.
+#[derive(Debug, Clone, Copy)]
+struct RequestTimeout(Duration);
+
+impl RequestTimeout {
+    /// Time kept to finish the current step and respond.
+    const MARGIN: Duration = Duration::from_millis(500);
+
+    /// When a request starting now must stop taking new steps.
+    fn deadline(self) -> Instant {
+        Instant::now() + self.0.saturating_sub(Self::MARGIN)
     }
 }
 
-async fn advices_generate() -> Result<Advice, anyhow::Error> {
-    let resp = reqwest::get("https://api.adviceslip.com/advice")
+/// Most advices a batch may request with the server's configuration.
+#[derive(Debug, Clone, Copy)]
+struct MaxBatchCount(usize);
+
+/// Fetches `count` distinct advices from the provider and stores the ones
+/// that are new.
+///
+/// Rounds of calls stop short of the request timeout, and the advices fetched
+/// by then are reported rather than lost to a timeout.
+async fn advices_batch(
+    query: Result<Query<BatchParams>, QueryRejection>,
+    Extension(store): Extension<Store>,
+    Extension(provider): Extension<Provider>,
+    Extension(timeout): Extension<RequestTimeout>,
+    Extension(MaxBatchCount(max_count)): Extension<MaxBatchCount>,
+    principal: Option<Principal>,
+) -> Result<impl IntoResponse> {
+    let created_by = principal.map(|principal| principal.id);
+    let Query(params) = query.map_err(bad_request)?;
+    let deadline = timeout.deadline();
+    let count = params.count;
+    if count == 0 || count > max_count {
+        return Err(Error::BadRequest(format!(
+            "count must be between 1 and {}",
+            max_count
+        )));
+    }
+
+    let mut report = BatchReport::default();
+    let mut seen = HashSet::new();
+    let mut last_error = None;
+    let mut attempts_left = count * BATCH_ATTEMPTS_PER_ADVICE;
+    let mut retry = false;
+    while seen.len() < count && attempts_left > 0 {
+        if retry {
+            // A round that cannot get a call through in time is not started.
+            if Instant::now() + BATCH_RETRY_DELAY >= deadline {
+                break;
+            }
+            tokio::time::sleep(BATCH_RETRY_DELAY).await;
+        }
+        retry = true;
+
+        let wanted = (count - seen.len()).min(attempts_left);
+        attempts_left -= wanted;
+        let results = stream::iter(0..wanted)
+            .map(|_| tokio::time::timeout_at(deadline.into(), provider.random()))
+            .buffer_unordered(BATCH_CONCURRENCY)
+            .collect::<Vec<_>>()
+            .await;
+
+        for result in results {
+            let mut advice = match result.unwrap_or(Err(Error::Timeout)) {
+                Ok(advice) => advice,
+                Err(error) => {
+                    tracing::warn!("batch fetch failed: {:#}", error);
+                    report.failed += 1;
+                    last_error = Some(error);
+                    continue;
+                }
+            };
+
+            if !seen.insert(advice.id) {
+                report.duplicates += 1;
+            } else if store.get(advice.id).await?.is_some() {
+                report.known += 1;
+            } else {
+                advice.created_by = created_by.clone();
+                match store.insert(advice).await {
+                    Ok(()) => report.inserted += 1,
+                    Err(Error::Conflict(_)) => report.similar += 1,
+                    Err(error) => return Err(error),
+                }
+            }
+        }
+    }
+
+    // Only fail when nothing could be fetched at all.
+    match last_error {
+        Some(error) if seen.is_empty() => Err(error),
+        _ => Ok(Json(report)),
+    }
+}
+
+/// Exactly one of the two is expected.
+#[derive(Debug, Deserialize)]
+struct ImportUpstreamParams {
+    id: Option<i64>,
+    search: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct ImportUpstreamReport {
+    inserted: usize,
+    known: usize,
+    /// Advices not stored because their text duplicates a stored advice.
+    similar: usize,
+    /// The advices the provider returned that are stored, as they are now.
+    advices: Vec<Advice>,
+}
+
+/// Stores the provider's advice with a given id, or all advices matching a
+/// search. Advices already stored are left as they are.
+async fn advices_import_upstream(
+    query: Result<Query<ImportUpstreamParams>, QueryRejection>,
+    Extension(store): Extension<Store>,
+    Extension(provider): Extension<Provider>,
+    principal: Option<Principal>,
+) -> Result<impl IntoResponse> {
+    let created_by = principal.map(|principal| principal.id);
+    let Query(params) = query.map_err(bad_request)?;
+    let advices = match (params.id, params.search) {
+        (Some(id), None) => vec![provider.by_id(id).await?.ok_or(Error::NotFound)?],
+        (None, Some(search)) if !search.trim().is_empty() => provider.search(search.trim()).await?,
+        _ => {
+            return Err(Error::BadRequest(
+                "either id or a non-empty search is required".to_owned(),
+            ))
+        }
+    };
+
+    let mut report = ImportUpstreamReport::default();
+    for mut advice in advices {
+        match store.get(advice.id).await? {
+            Some(stored) => {
+                report.known += 1;
+                report.advices.push(stored);
+            }
+            None => {
+                advice.created_by = created_by.clone();
+                match store.insert(advice.clone()).await {
+                    Ok(()) => {
+                        report.inserted += 1;
+                        report.advices.push(advice);
+                    }
+                    Err(Error::Conflict(_)) => report.similar += 1,
+                    Err(error) => return Err(error),
+                }
+            }
+        }
+    }
+
+    Ok(Json(report))
+}
+
+async fn advices_show(
+    path: Result<Path<i64>, PathParamsRejection>,
+    Extension(store): Extension<Store>,
+    headers: HeaderMap,
+) -> Result<Response<BoxBody>> {
+    let Path(id) = path.map_err(bad_request)?;
+    let advice = store.get(id).await?.ok_or(Error::NotFound)?;
+
+    let etag = etag::of(&advice);
+    let mut response_headers = HeaderMap::new();
+    response_headers.insert(header::ETAG, etag.clone());
+
+    let response = if etag::none_match(&headers, &etag) {
+        (StatusCode::NOT_MODIFIED, response_headers, ())
+            .into_response()
+            .map(box_body)
+    } else {
+        (response_headers, Json(advice))
+            .into_response()
+            .map(box_body)
+    };
+
+    Ok(response)
+}
+
+#[derive(Debug, Deserialize)]
+struct RandomParams {
+    tag: Option<String>,
+    /// Picks the same advice for the same seed while the store is unchanged.
+    seed: Option<u64>,
+}
+
+/// Picks a stored advice uniformly at random, without calling upstream.
+async fn advices_random(
+    query: Result<Query<RandomParams>, QueryRejection>,
+    Extension(store): Extension<Store>,
+) -> Result<impl IntoResponse> {
+    let Query(params) = query.map_err(bad_request)?;
+    let tag = params
+        .tag
+        .as_deref()
+        .map(Advice::validate_tag)
+        .transpose()?;
+
+    let count = store.count(tag.as_deref()).await?;
+    if count == 0 {
+        return Err(Error::NotFound);
+    }
+    let index = match params.seed {
+        Some(seed) => pick::index(seed, count),
+        None => rand::thread_rng().gen_range(0..count),
+    };
+
+    let advice = store
+        .nth(tag.as_deref(), index)
         .await?
-        .json::<HashMap<String, Advice>>()
-        .await?;
+        .ok_or(Error::NotFound)?;
 
-    let advice = resp.get("slip").unwrap();
-    Ok(advice.clone())
+    Ok(Json(advice))
 }
 
-type Db = Arc<RwLock<HashMap<i64, Advice>>>;
+#[derive(Debug, Deserialize)]
+struct DailyParams {
+    /// IANA time zone deciding when the day changes; UTC by default.
+    tz: Option<String>,
+}
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct Advice {
-    id: i64,
+#[derive(Debug, Serialize)]
+struct DailyAdvice {
+    date: NaiveDate,
+    tz: String,
+    advice: Advice,
+}
+
+/// Picks the advice of the day from the store, and records the pick so that
+/// advices stored or deleted during the day don't change it. The first pick
+/// of a day only depends on the date and the stored advices, so replicas
+/// sharing a store agree on it even when they race to record it. A new one is
+/// picked if the advice of the day is deleted.
+async fn advices_daily(
+    query: Result<Query<DailyParams>, QueryRejection>,
+    Extension(store): Extension<Store>,
+) -> Result<impl IntoResponse> {
+    let Query(params) = query.map_err(bad_request)?;
+    let tz = match params.tz.as_deref() {
+        Some(name) => name
+            .parse::<Tz>()
+            .map_err(|_| Error::BadRequest(format!("unknown time zone {:?}", name)))?,
+        None => Tz::UTC,
+    };
+    let date = Utc::now().with_timezone(&tz).date_naive();
+    let advice = daily_advice(&store, date, &format!("{} {}", date, tz.name())).await?;
+
+    Ok(Json(DailyAdvice {
+        date,
+        tz: tz.name().to_owned(),
+        advice,
+    }))
+}
+
+/// The advice recorded for `day`, or a new pick for `date` if there is none
+/// or it was deleted.
+async fn daily_advice(store: &Store, date: NaiveDate, day: &str) -> Result<Advice> {
+    let stale = match store.daily_pick(day).await? {
+        Some(id) => match store.get(id).await? {
+            Some(advice) => return Ok(advice),
+            None => Some(id),
+        },
+        None => None,
+    };
+
+    let count = store.count(None).await?;
+    if count == 0 {
+        return Err(Error::NotFound);
+    }
+    let index = pick::index(pick::hash(&date.to_string()), count);
+    let candidate = store.nth(None, index).await?.ok_or(Error::NotFound)?;
+
+    match store.record_daily_pick(day, candidate.id, stale).await? {
+        id if id == candidate.id => Ok(candidate),
+        id => store.get(id).await?.ok_or(Error::NotFound),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchParams {
+    q: String,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct SearchResult {
+    advice: Advice,
+    score: f64,
+    /// Parts of the advice text matching the query.
+    highlights: Vec<Highlight>,
+}
+
+const DEFAULT_SEARCH_LIMIT: usize = 20;
+const MAX_SEARCH_LIMIT: usize = 100;
+
+async fn advices_search(
+    query: Result<Query<SearchParams>, QueryRejection>,
+    Extension(store): Extension<Store>,
+    Extension(search_index): Extension<Arc<SearchIndex>>,
+) -> Result<impl IntoResponse> {
+    let Query(params) = query.map_err(bad_request)?;
+    if search::tokenize(&params.q).next().is_none() {
+        return Err(Error::BadRequest(
+            "q must contain at least one word".to_owned(),
+        ));
+    }
+    let limit = params.limit.unwrap_or(DEFAULT_SEARCH_LIMIT);
+    if limit == 0 || limit > MAX_SEARCH_LIMIT {
+        return Err(Error::BadRequest(format!(
+            "limit must be between 1 and {}",
+            MAX_SEARCH_LIMIT
+        )));
+    }
+
+    let mut results = Vec::new();
+    for hit in search_index.search(&params.q, limit) {
+        // Skip advices deleted since the search ran.
+        if let Some(advice) = store.get(hit.id).await? {
+            results.push(SearchResult {
+                highlights: search::highlights(&advice.advice, &params.q),
+                score: hit.score,
+                advice,
+            });
+        }
+    }
+
+    Ok(Json(results))
+}
+
+#[derive(Debug, Deserialize)]
+struct DuplicatesParams {
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct DuplicatesReport {
+    /// Number of pairs found, of which the first `limit` are listed.
+    total: usize,
+    pairs: Vec<DuplicatePair>,
+}
+
+#[derive(Debug, Serialize)]
+struct DuplicatePair {
+    similarity: f64,
+    exact: bool,
+    advices: [Advice; 2],
+}
+
+const DEFAULT_DUPLICATES_LIMIT: usize = 50;
+
+/// Lists pairs of stored advices with the same or similar text, most similar
+/// first. Advices stored before duplicates were rejected can show up here.
+async fn advices_duplicates(
+    query: Result<Query<DuplicatesParams>, QueryRejection>,
+    Extension(store): Extension<Store>,
+    Extension(duplicates): Extension<Arc<DuplicateIndex>>,
+) -> Result<impl IntoResponse> {
+    let Query(params) = query.map_err(bad_request)?;
+    let limit = params.limit.unwrap_or(DEFAULT_DUPLICATES_LIMIT);
+    if limit == 0 || limit > ListQuery::MAX_LIMIT {
+        return Err(Error::BadRequest(format!(
+            "limit must be between 1 and {}",
+            ListQuery::MAX_LIMIT
+        )));
+    }
+
+    let found = duplicates.pairs();
+    let mut pairs = Vec::new();
+    for pair in found.iter().take(limit) {
+        let (a, b) = pair.ids;
+        // Skip advices deleted since the pairs were computed.
+        if let (Some(a), Some(b)) = (store.get(a).await?, store.get(b).await?) {
+            pairs.push(DuplicatePair {
+                similarity: pair.similarity,
+                exact: pair.exact,
+                advices: [a, b],
+            });
+        }
+    }
+
+    Ok(Json(DuplicatesReport {
+        total: found.len(),
+        pairs,
+    }))
+}
+
+/// Outcome of an import. Rows count records from 1, including the CSV header
+/// and blank lines.
+#[derive(Debug, Default, Serialize)]
+struct ImportReport {
+    imported: usize,
+    failed: usize,
+    /// Rows read. An import stopped by the request timeout can be resumed
+    /// with the rows after these.
+    rows: usize,
+    /// Whether the whole body was read.
+    complete: bool,
+    /// The first `MAX_IMPORT_ERRORS` failures.
+    errors: Vec<ImportError>,
+}
+
+#[derive(Debug, Serialize)]
+struct ImportError {
+    row: usize,
+    code: &'static str,
+    message: String,
+}
+
+const MAX_IMPORT_ERRORS: usize = 100;
+
+impl ImportReport {
+    fn fail(&mut self, row: usize, error: Error) {
+        self.failed += 1;
+        if self.errors.len() < MAX_IMPORT_ERRORS {
+            self.errors.push(ImportError {
+                row,
+                code: error.code(),
+                message: error.to_string(),
+            });
+        }
+    }
+}
+
+/// Stores every advice of the body, replacing advices with the same id. The
+/// body is decoded as it arrives, in the format given by `Content-Type`.
+/// Invalid rows are reported and skipped; a body that cannot be split into
+/// rows ends the import at that point, and so does the request timeout
+/// coming close.
+///
+/// Imported advices are recorded as created by the importer, except for
+/// admins, who restore backups with the `created_by` they hold.
+async fn advices_import(
+    mut body: BodyStream,
+    Extension(store): Extension<Store>,
+    Extension(timeout): Extension<RequestTimeout>,
+    principal: Option<Principal>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse> {
+    let deadline = timeout.deadline();
+    // What to overwrite `created_by` with, if anything.
+    let created_by = match principal {
+        Some(principal) if principal.allows(Scope::Admin) => None,
+        Some(principal) => Some(Some(principal.id)),
+        None => Some(None),
+    };
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    let mut decoder = Decoder::new(Format::from_content_type(content_type)?);
+
+    let mut report = ImportReport::default();
+    let mut row = 0;
+    let mut done = false;
+    'import: while !done {
+        let next = tokio::time::timeout_at(deadline.into(), body.next()).await;
+        let records = match next {
+            Err(_) => break,
+            Ok(Some(chunk)) => {
+                let chunk = chunk.map_err(|error| {
+                    Error::BadRequest(format!("failed to read request body: {}", error))
+                })?;
+                decoder.push(&chunk)
+            }
+            Ok(None) => {
+                done = true;
+                decoder.finish().map(|record| record.into_iter().collect())
+            }
+        };
+        let records = match records {
+            Ok(records) => records,
+            Err(error) => {
+                report.fail(row + 1, error);
+                break;
+            }
+        };
+
+        for record in records {
+            if Instant::now() >= deadline {
+                done = false;
+                break 'import;
+            }
+            row += 1;
+            match decoder.decode(&record) {
+                Ok(Some(mut advice)) => {
+                    if let Some(created_by) = &created_by {
+                        advice.created_by = created_by.clone();
+                    }
+                    match store.insert(advice).await {
+                        Ok(()) => report.imported += 1,
+                        Err(error @ Error::Conflict(_)) => report.fail(row, error),
+                        Err(error) => return Err(error),
+                    }
+                }
+                Ok(None) => {}
+                Err(error) => report.fail(row, error),
+            }
+        }
+    }
+    report.rows = row;
+    report.complete = done;
+
+    Ok(Json(report))
+}
+
+/// Position of an export in the store.
+struct Export {
+    store: Store,
+    encoder: Encoder,
+    /// Where the next page starts, `None` once the last one has been sent.
+    next: Option<Option<Cursor>>,
+}
+
+/// Streams every stored advice in id order, in the format asked for by
+/// `Accept`. Advices are read a page at a time, so the export never holds
+/// the whole store.
+async fn advices_export(
+    Extension(store): Extension<Store>,
+    headers: HeaderMap,
+) -> Result<Response<BoxBody>> {
+    let accept = headers
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok());
+    let format = Format::from_accept(accept)?;
+
+    let encoder = Encoder::new(format);
+    let start = Bytes::from(encoder.start());
+    let export = Export {
+        store,
+        encoder,
+        next: Some(None),
+    };
+    let pages = stream::once(async move { Ok::<_, Error>(start) })
+        .chain(stream::try_unfold(export, export_page));
+
+    let response = Response::builder()
+        .header(header::CONTENT_TYPE, format.content_type())
+        .body(box_body(Body::wrap_stream(pages)))
+        .map_err(anyhow::Error::from)?;
+
+    Ok(response)
+}
+
+async fn export_page(mut export: Export) -> Result<Option<(Bytes, Export)>> {
+    let after = match export.next.take() {
+        Some(after) => after,
+        None => return Ok(None),
+    };
+
+    let query = ListQuery {
+        limit: ListQuery::MAX_LIMIT,
+        after,
+        ..ListQuery::default()
+    };
+    let page = export.store.list(&query).await?;
+
+    let mut chunk = export.encoder.encode(&page.advices)?;
+    match page.next_cursor {
+        Some(cursor) => export.next = Some(Some(cursor)),
+        None => chunk.extend(export.encoder.finish()),
+    }
+
+    Ok(Some((Bytes::from(chunk), export)))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AdviceReplacement {
     advice: String,
+    #[serde(default)]
+    tags: Vec<String>,
+    author: Option<String>,
+}
+
+/// Fields left out are kept as they are; an empty `author` removes it.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AdvicePatch {
+    advice: Option<String>,
+    tags: Option<Vec<String>>,
+    author: Option<String>,
+}
+
+async fn advices_replace(
+    path: Result<Path<i64>, PathParamsRejection>,
+    body: Result<Json<AdviceReplacement>, JsonRejection>,
+    Extension(store): Extension<Store>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse> {
+    let Path(id) = path.map_err(bad_request)?;
+    let Json(replacement) = body.map_err(bad_request)?;
+    let patch = AdvicePatch {
+        advice: Some(replacement.advice),
+        tags: Some(replacement.tags),
+        author: Some(replacement.author.unwrap_or_default()),
+    };
+
+    advices_edit(id, &headers, patch, &store).await
+}
+
+async fn advices_patch(
+    path: Result<Path<i64>, PathParamsRejection>,
+    body: Result<Json<AdvicePatch>, JsonRejection>,
+    Extension(store): Extension<Store>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse> {
+    let Path(id) = path.map_err(bad_request)?;
+    let Json(patch) = body.map_err(bad_request)?;
+    advices_edit(id, &headers, patch, &store).await
+}
+
+/// Applies `patch` to the stored advice. Without `If-Match`, the update is
+/// still conditional on the version read here, so concurrent edits are never
+/// lost silently.
+async fn advices_edit(
+    id: i64,
+    headers: &HeaderMap,
+    patch: AdvicePatch,
+    store: &Store,
+) -> Result<impl IntoResponse> {
+    let mut advice = store.get(id).await?.ok_or(Error::NotFound)?;
+    let expected_version = etag::if_match(headers, &advice)?.unwrap_or(advice.version);
+
+    if let Some(text) = patch.advice {
+        advice.advice = Advice::validate_text(&text)?;
+    }
+    if let Some(tags) = patch.tags {
+        advice.tags = Advice::validate_tags(&tags)?;
+    }
+    if let Some(author) = patch.author {
+        advice.author = Advice::validate_author(&author)?;
+    }
+
+    let advice = store.update(advice, Some(expected_version)).await?;
+
+    Ok(with_etag(advice))
+}
+
+async fn advices_delete(
+    path: Result<Path<i64>, PathParamsRejection>,
+    Extension(store): Extension<Store>,
+) -> Result<impl IntoResponse> {
+    let Path(id) = path.map_err(bad_request)?;
+    if store.delete(id).await? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(Error::NotFound)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AdviceTags {
+    tags: Vec<String>,
+}
+
+async fn advice_tags_attach(
+    path: Result<Path<i64>, PathParamsRejection>,
+    body: Result<Json<AdviceTags>, JsonRejection>,
+    Extension(store): Extension<Store>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse> {
+    let Path(id) = path.map_err(bad_request)?;
+    let Json(body) = body.map_err(bad_request)?;
+    let tags = Advice::validate_tags(&body.tags)?;
+    let current = store.get(id).await?.ok_or(Error::NotFound)?;
+    let advice = store
+        .attach_tags(id, &tags, etag::if_match(&headers, &current)?)
+        .await?;
+
+    Ok(with_etag(advice))
+}
+
+async fn advice_tags_detach(
+    path: Result<Path<(i64, String)>, PathParamsRejection>,
+    Extension(store): Extension<Store>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse> {
+    let Path((id, tag)) = path.map_err(bad_request)?;
+    let tag = Advice::validate_tag(&tag)?;
+    let current = store.get(id).await?.ok_or(Error::NotFound)?;
+    let advice = store
+        .detach_tag(id, &tag, etag::if_match(&headers, &current)?)
+        .await?;
+
+    Ok(with_etag(advice))
+}
+
+/// Responds with `advice` and its entity tag.
+fn with_etag(advice: Advice) -> impl IntoResponse {
+    let mut headers = HeaderMap::new();
+    headers.insert(header::ETAG, etag::of(&advice));
+
+    (headers, Json(advice))
+}
+
+async fn tags_index(Extension(store): Extension<Store>) -> Result<impl IntoResponse> {
+    Ok(Json(store.tags().await?))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NewTag {
+    name: String,
+}
+
+async fn tags_create(
+    body: Result<Json<NewTag>, JsonRejection>,
+    Extension(store): Extension<Store>,
+) -> Result<impl IntoResponse> {
+    let Json(new) = body.map_err(bad_request)?;
+    let tag = store.create_tag(&Advice::validate_tag(&new.name)?).await?;
+
+    Ok((StatusCode::CREATED, Json(tag)))
 }
+
+async fn tags_delete(
+    path: Result<Path<String>, PathParamsRejection>,
+    Extension(store): Extension<Store>,
+) -> Result<impl IntoResponse> {
+    let Path(name) = path.map_err(bad_request)?;
+    let name = Advice::validate_tag(&name)?;
+    if store.delete_tag(&name).await? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(Error::TagNotFound(name))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NewKey {
+    name: String,
+    scopes: Vec<Scope>,
+}
+
+/// Longest API key name accepted, in characters.
+const MAX_KEY_NAME_LENGTH: usize = 100;
+
+#[derive(Debug, Serialize)]
+struct IssuedKey {
+    #[serde(flatten)]
+    key: ApiKey,
+    /// The key itself, only ever shown here.
+    token: String,
+}
+
+async fn keys_index(Extension(keys): Extension<Keys>) -> Result<impl IntoResponse> {
+    Ok(Json(keys.keys().await?))
+}
+
+async fn keys_create(
+    body: Result<Json<NewKey>, JsonRejection>,
+    Extension(keys): Extension<Keys>,
+) -> Result<impl IntoResponse> {
+    let Json(new) = body.map_err(bad_request)?;
+    let name = new.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_KEY_NAME_LENGTH {
+        return Err(Error::BadRequest(format!(
+            "name must be between 1 and {} characters",
+            MAX_KEY_NAME_LENGTH
+        )));
+    }
+    if new.scopes.is_empty() {
+        return Err(Error::BadRequest("scopes must not be empty".to_owned()));
+    }
+
+    let (key, token) = ApiKey::generate(name, new.scopes);
+    keys.insert_key(key.clone()).await?;
+    tracing::info!("issued API key {} ({})", key.id, key.name);
+
+    Ok((StatusCode::CREATED, Json(IssuedKey { key, token })))
+}
+
+async fn keys_delete(
+    path: Result<Path<String>, PathParamsRejection>,
+    Extension(keys): Extension<Keys>,
+) -> Result<impl IntoResponse> {
+    let Path(id) = path.map_err(bad_request)?;
+    if keys.revoke_key(&id).await? {
+        tracing::info!("revoked API key {}", id);
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(Error::KeyNotFound(id))
+    }
+}
+
+/// Parses a request body that must be sent as `application/json` or another
+/// `+json` media type.
+fn json_body<T: DeserializeOwned>(headers: &HeaderMap, body: &[u8]) -> Result<T> {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    if media_type != "application/json" && !media_type.ends_with("+json") {
+        return Err(Error::UnsupportedMediaType(format!(
+            "expected an application/json body, not {:?}",
+            content_type
+        )));
+    }
+
+    serde_json::from_slice(body).map_err(bad_request)
+}
+
+/// Reports a request that an extractor rejected as a bad request, so that it
+/// gets the same JSON body as other errors rather than axum's plain text.
+fn bad_request(rejection: impl ToString) -> Error {
+    Error::BadRequest(rejection.to_string())
+}
+
+type Store = Arc<dyn AdviceStore>;
+type Provider = Arc<dyn AdviceProvider>;
+type Keys = Arc<dyn KeyStore>;
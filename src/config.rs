@@ -0,0 +1,532 @@
+use crate::{provider::AdviceSlip, rate_limit::Limit};
+use anyhow::{anyhow, bail, Context};
+use serde::Deserialize;
+use std::{
+    env,
+    fmt::Display,
+    fs,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+/// Shortest bootstrap key accepted, so that it cannot be guessed.
+const MIN_BOOTSTRAP_KEY_LENGTH: usize = 32;
+
+/// Config file read when `ADVICES_CONFIG` is not set, if it exists.
+const DEFAULT_CONFIG_FILE: &str = "advices.toml";
+
+/// Server configuration.
+///
+/// Values come from the TOML file named by `ADVICES_CONFIG` (or
+/// `advices.toml` in the working directory), then `ADVICES_<SECTION>_<KEY>`
+/// environment variables override individual settings.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Default tracing filter, used when `RUST_LOG` is not set.
+    pub log: String,
+    pub server: ServerConfig,
+    pub upstream: UpstreamConfig,
+    pub storage: StorageConfig,
+    pub auth: AuthConfig,
+    pub rate_limit: RateLimitConfig,
+    pub health: HealthConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: IpAddr,
+    pub port: u16,
+    /// Deadline for handling a whole request.
+    pub request_timeout_ms: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamConfig {
+    /// Base URL of the adviceslip API.
+    pub url: String,
+    /// Deadline for connecting to upstream.
+    pub connect_timeout_ms: u64,
+    /// Deadline for a single attempt of an upstream call, from connecting to
+    /// reading the body.
+    pub timeout_ms: u64,
+    /// Retries of calls failing with a network error or a 5xx status.
+    pub retries: u32,
+    /// Delay before the first retry, doubled on every following one.
+    pub backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    /// Consecutive failed calls after which upstream is not called for
+    /// `breaker_cooldown_ms`.
+    pub breaker_threshold: u32,
+    pub breaker_cooldown_ms: u64,
+    /// Reuse advices by id and search results from upstream for this long,
+    /// and past it while upstream fails. Zero disables the cache.
+    pub cache_ttl_ms: u64,
+    /// Serve a corpus or stored advice from `POST /advices` when upstream
+    /// fails or takes longer than `fallback_deadline_ms`. Its upstream calls
+    /// are then cut off at that deadline, retries included.
+    pub fallback: bool,
+    pub fallback_deadline_ms: u64,
+    /// Serve advices from this JSON/JSONL file instead of calling upstream.
+    pub corpus: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    /// SQLite database file; advices are kept in memory when unset.
+    pub database: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Require API keys for writes. `/admin` always requires an admin key.
+    /// The server won't start with this enabled and no way to authenticate:
+    /// no bootstrap key, no JWKS and no issued keys.
+    pub enabled: bool,
+    /// Require API keys for reads too.
+    pub require_read: bool,
+    /// Key granting admin access without being issued, to issue the first
+    /// keys with.
+    pub bootstrap_key: Option<String>,
+    /// JSON Web Key Set of an identity provider, as a file path or an
+    /// http(s) URL, to accept its JWTs as well as API keys.
+    pub jwks: Option<String>,
+    /// Required `iss` claim of JWTs.
+    pub jwt_issuer: Option<String>,
+    /// Required `aud` claim of JWTs.
+    pub jwt_audience: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Limit requests per API key, or per IP address for anonymous clients.
+    pub enabled: bool,
+    /// Requests a client may make at once, then per minute, on routes that
+    /// don't call upstream.
+    pub read_burst: u32,
+    pub read_per_minute: u32,
+    /// The same for routes that call upstream, where a batch counts once per
+    /// advice requested and may not request more than the burst.
+    pub upstream_burst: u32,
+    pub upstream_per_minute: u32,
+    /// The same for failed authentications, per IP address, whoever they
+    /// claimed to be.
+    pub auth_failure_burst: u32,
+    pub auth_failure_per_minute: u32,
+    /// Take client IP addresses from `X-Forwarded-For`. Only enable behind a
+    /// reverse proxy that sets it.
+    pub trust_forwarded_for: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    /// Report whether upstream responds in `/readyz`. Its failures don't make
+    /// the server not ready.
+    pub check_upstream: bool,
+    /// How long an upstream check result is reused.
+    pub upstream_ttl_ms: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            log: "tower_http=debug".to_owned(),
+            server: ServerConfig::default(),
+            upstream: UpstreamConfig::default(),
+            storage: StorageConfig::default(),
+            auth: AuthConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            health: HealthConfig::default(),
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: [0, 0, 0, 0].into(),
+            port: 3000,
+            request_timeout_ms: 10_000,
+        }
+    }
+}
+
+impl Default for UpstreamConfig {
+    fn default() -> Self {
+        Self {
+            url: AdviceSlip::DEFAULT_URL.to_owned(),
+            connect_timeout_ms: 2_000,
+            timeout_ms: 2_500,
+            retries: 2,
+            backoff_ms: 100,
+            max_backoff_ms: 1_000,
+            breaker_threshold: 5,
+            breaker_cooldown_ms: 30_000,
+            cache_ttl_ms: 300_000,
+            fallback: true,
+            fallback_deadline_ms: 2_000,
+            corpus: None,
+        }
+    }
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            require_read: false,
+            bootstrap_key: None,
+            jwks: None,
+            jwt_issuer: None,
+            jwt_audience: None,
+        }
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            read_burst: 100,
+            read_per_minute: 600,
+            upstream_burst: 10,
+            upstream_per_minute: 30,
+            auth_failure_burst: 10,
+            auth_failure_per_minute: 10,
+            trust_forwarded_for: false,
+        }
+    }
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            check_upstream: false,
+            upstream_ttl_ms: 30_000,
+        }
+    }
+}
+
+impl Config {
+    /// Loads the config file and environment overrides, then validates the
+    /// result.
+    pub fn load() -> anyhow::Result<Self> {
+        let mut config = match env::var_os("ADVICES_CONFIG") {
+            Some(path) => Self::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Self::from_file(DEFAULT_CONFIG_FILE)?
+            }
+            None => Self::default(),
+        };
+
+        config.apply_env()?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+
+        toml::from_str(&content)
+            .with_context(|| format!("failed to parse config file {}", path.display()))
+    }
+
+    fn apply_env(&mut self) -> anyhow::Result<()> {
+        override_from_env("ADVICES_LOG", &mut self.log)?;
+
+        override_from_env("ADVICES_SERVER_BIND", &mut self.server.bind)?;
+        override_from_env("ADVICES_SERVER_PORT", &mut self.server.port)?;
+        override_from_env(
+            "ADVICES_SERVER_REQUEST_TIMEOUT_MS",
+            &mut self.server.request_timeout_ms,
+        )?;
+
+        override_from_env("ADVICES_UPSTREAM_URL", &mut self.upstream.url)?;
+        override_from_env(
+            "ADVICES_UPSTREAM_CONNECT_TIMEOUT_MS",
+            &mut self.upstream.connect_timeout_ms,
+        )?;
+        override_from_env("ADVICES_UPSTREAM_TIMEOUT_MS", &mut self.upstream.timeout_ms)?;
+        override_from_env("ADVICES_UPSTREAM_RETRIES", &mut self.upstream.retries)?;
+        override_from_env("ADVICES_UPSTREAM_BACKOFF_MS", &mut self.upstream.backoff_ms)?;
+        override_from_env(
+            "ADVICES_UPSTREAM_MAX_BACKOFF_MS",
+            &mut self.upstream.max_backoff_ms,
+        )?;
+        override_from_env(
+            "ADVICES_UPSTREAM_BREAKER_THRESHOLD",
+            &mut self.upstream.breaker_threshold,
+        )?;
+        override_from_env(
+            "ADVICES_UPSTREAM_BREAKER_COOLDOWN_MS",
+            &mut self.upstream.breaker_cooldown_ms,
+        )?;
+        override_from_env(
+            "ADVICES_UPSTREAM_CACHE_TTL_MS",
+            &mut self.upstream.cache_ttl_ms,
+        )?;
+        override_from_env("ADVICES_UPSTREAM_FALLBACK", &mut self.upstream.fallback)?;
+        override_from_env(
+            "ADVICES_UPSTREAM_FALLBACK_DEADLINE_MS",
+            &mut self.upstream.fallback_deadline_ms,
+        )?;
+        override_path_from_env("ADVICES_UPSTREAM_CORPUS", &mut self.upstream.corpus);
+
+        override_path_from_env("ADVICES_STORAGE_DATABASE", &mut self.storage.database);
+
+        override_from_env("ADVICES_AUTH_ENABLED", &mut self.auth.enabled)?;
+        override_from_env("ADVICES_AUTH_REQUIRE_READ", &mut self.auth.require_read)?;
+        override_option_from_env("ADVICES_AUTH_BOOTSTRAP_KEY", &mut self.auth.bootstrap_key);
+        override_option_from_env("ADVICES_AUTH_JWKS", &mut self.auth.jwks);
+        override_option_from_env("ADVICES_AUTH_JWT_ISSUER", &mut self.auth.jwt_issuer);
+        override_option_from_env("ADVICES_AUTH_JWT_AUDIENCE", &mut self.auth.jwt_audience);
+
+        override_from_env("ADVICES_RATE_LIMIT_ENABLED", &mut self.rate_limit.enabled)?;
+        override_from_env(
+            "ADVICES_RATE_LIMIT_READ_BURST",
+            &mut self.rate_limit.read_burst,
+        )?;
+        override_from_env(
+            "ADVICES_RATE_LIMIT_READ_PER_MINUTE",
+            &mut self.rate_limit.read_per_minute,
+        )?;
+        override_from_env(
+            "ADVICES_RATE_LIMIT_UPSTREAM_BURST",
+            &mut self.rate_limit.upstream_burst,
+        )?;
+        override_from_env(
+            "ADVICES_RATE_LIMIT_UPSTREAM_PER_MINUTE",
+            &mut self.rate_limit.upstream_per_minute,
+        )?;
+        override_from_env(
+            "ADVICES_RATE_LIMIT_AUTH_FAILURE_BURST",
+            &mut self.rate_limit.auth_failure_burst,
+        )?;
+        override_from_env(
+            "ADVICES_RATE_LIMIT_AUTH_FAILURE_PER_MINUTE",
+            &mut self.rate_limit.auth_failure_per_minute,
+        )?;
+        override_from_env(
+            "ADVICES_RATE_LIMIT_TRUST_FORWARDED_FOR",
+            &mut self.rate_limit.trust_forwarded_for,
+        )?;
+
+        override_from_env(
+            "ADVICES_HEALTH_CHECK_UPSTREAM",
+            &mut self.health.check_upstream,
+        )?;
+        override_from_env(
+            "ADVICES_HEALTH_UPSTREAM_TTL_MS",
+            &mut self.health.upstream_ttl_ms,
+        )?;
+
+        Ok(())
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.log.trim().is_empty() {
+            bail!("log must not be empty");
+        }
+        if self.server.port == 0 {
+            bail!("server.port must not be 0");
+        }
+        if self.server.request_timeout_ms == 0 {
+            bail!("server.request_timeout_ms must be greater than 0");
+        }
+        if self.upstream.connect_timeout_ms == 0 {
+            bail!("upstream.connect_timeout_ms must be greater than 0");
+        }
+        if self.upstream.timeout_ms == 0 {
+            bail!("upstream.timeout_ms must be greater than 0");
+        }
+        // Otherwise the request times out while upstream calls are still
+        // being retried, which hides their failures from the breaker.
+        let longest_call = self.upstream.longest_call().as_millis();
+        if longest_call > u128::from(self.server.request_timeout_ms) {
+            bail!(
+                "upstream calls may take up to {} ms with upstream.timeout_ms, \
+                upstream.retries and upstream.max_backoff_ms, more than the {} ms of \
+                server.request_timeout_ms",
+                longest_call,
+                self.server.request_timeout_ms
+            );
+        }
+        if self.upstream.backoff_ms > self.upstream.max_backoff_ms {
+            bail!("upstream.backoff_ms must not be greater than upstream.max_backoff_ms");
+        }
+        if self.upstream.breaker_threshold == 0 {
+            bail!("upstream.breaker_threshold must be greater than 0");
+        }
+        if self.upstream.fallback && self.upstream.fallback_deadline_ms == 0 {
+            bail!("upstream.fallback_deadline_ms must be greater than 0");
+        }
+
+        let url = reqwest::Url::parse(&self.upstream.url)
+            .with_context(|| format!("upstream.url {:?} is not a valid URL", self.upstream.url))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            bail!(
+                "upstream.url {:?} must be an http(s) URL",
+                self.upstream.url
+            );
+        }
+
+        if let Some(key) = &self.auth.bootstrap_key {
+            if key.len() < MIN_BOOTSTRAP_KEY_LENGTH {
+                bail!(
+                    "auth.bootstrap_key must be at least {} characters",
+                    MIN_BOOTSTRAP_KEY_LENGTH
+                );
+            }
+        }
+
+        // Without both, any token signed by the identity provider would do,
+        // including ones issued to other services.
+        if self.auth.jwks.is_some()
+            && (self.auth.jwt_issuer.is_none() || self.auth.jwt_audience.is_none())
+        {
+            bail!("auth.jwt_issuer and auth.jwt_audience are required with auth.jwks");
+        }
+
+        if self.rate_limit.enabled {
+            let limits = [
+                ("read_burst", self.rate_limit.read_burst),
+                ("read_per_minute", self.rate_limit.read_per_minute),
+                ("upstream_burst", self.rate_limit.upstream_burst),
+                ("upstream_per_minute", self.rate_limit.upstream_per_minute),
+                ("auth_failure_burst", self.rate_limit.auth_failure_burst),
+                (
+                    "auth_failure_per_minute",
+                    self.rate_limit.auth_failure_per_minute,
+                ),
+            ];
+            for (name, value) in limits {
+                if value == 0 {
+                    bail!("rate_limit.{} must be greater than 0", name);
+                }
+            }
+        }
+
+        if let Some(corpus) = &self.upstream.corpus {
+            if !corpus.is_file() {
+                bail!("upstream.corpus {} does not exist", corpus.display());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl ServerConfig {
+    pub fn addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind, self.port)
+    }
+
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_millis(self.request_timeout_ms)
+    }
+}
+
+impl RateLimitConfig {
+    pub fn read(&self) -> Limit {
+        Limit {
+            burst: self.read_burst,
+            per_minute: self.read_per_minute,
+        }
+    }
+
+    pub fn upstream(&self) -> Limit {
+        Limit {
+            burst: self.upstream_burst,
+            per_minute: self.upstream_per_minute,
+        }
+    }
+
+    pub fn auth_failure(&self) -> Limit {
+        Limit {
+            burst: self.auth_failure_burst,
+            per_minute: self.auth_failure_per_minute,
+        }
+    }
+}
+
+impl HealthConfig {
+    pub fn upstream_ttl(&self) -> Duration {
+        Duration::from_millis(self.upstream_ttl_ms)
+    }
+}
+
+impl UpstreamConfig {
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_millis(self.connect_timeout_ms)
+    }
+
+    /// Longest time an upstream call may take, every retry included.
+    pub fn longest_call(&self) -> Duration {
+        let retries = u64::from(self.retries);
+        Duration::from_millis(
+            self.timeout_ms
+                .saturating_mul(retries + 1)
+                .saturating_add(self.max_backoff_ms.saturating_mul(retries)),
+        )
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+
+    pub fn breaker_cooldown(&self) -> Duration {
+        Duration::from_millis(self.breaker_cooldown_ms)
+    }
+
+    pub fn cache_ttl(&self) -> Duration {
+        Duration::from_millis(self.cache_ttl_ms)
+    }
+
+    pub fn fallback_deadline(&self) -> Duration {
+        Duration::from_millis(self.fallback_deadline_ms)
+    }
+}
+
+fn override_from_env<T>(name: &str, target: &mut T) -> anyhow::Result<()>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    if let Ok(value) = env::var(name) {
+        *target = value
+            .parse()
+            .map_err(|e| anyhow!("invalid {}={:?}: {}", name, value, e))?;
+    }
+
+    Ok(())
+}
+
+/// An empty value clears the path, so a file setting can be unset from the
+/// environment.
+fn override_path_from_env(name: &str, target: &mut Option<PathBuf>) {
+    if let Some(value) = env::var_os(name) {
+        *target = if value.is_empty() {
+            None
+        } else {
+            Some(value.into())
+        };
+    }
+}
+
+/// An empty value clears the setting, as for paths.
+fn override_option_from_env(name: &str, target: &mut Option<String>) {
+    if let Ok(value) = env::var(name) {
+        *target = Some(value).filter(|value| !value.is_empty());
+    }
+}
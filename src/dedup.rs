@@ -0,0 +1,289 @@
+//! Detection of duplicate advice texts.
+//!
+//! Texts are normalised to their lowercased words, so case, punctuation and
+//! spacing don't matter. Texts without any words are not indexed, as they
+//! would all duplicate each other. Exact duplicates share the hash of the normalised
+//! text. Near duplicates are found with MinHash signatures over character
+//! shingles, bucketed by band so that only likely matches are compared.
+
+use crate::{pick, search, Advice};
+use serde::Serialize;
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    sync::{PoisonError, RwLock},
+};
+
+/// Estimated similarity from which two texts count as near duplicates.
+pub const NEAR_DUPLICATE_THRESHOLD: f64 = 0.8;
+
+/// Length of the character shingles compared between texts.
+const SHINGLE_LENGTH: usize = 4;
+/// Signatures are split into `BANDS` bands of `ROWS` hashes; texts sharing a
+/// band are compared. With 16 bands of 4, texts 80% similar share one with a
+/// probability above 99%, texts 40% similar with one around 35%.
+const BANDS: usize = 16;
+const ROWS: usize = 4;
+const SIGNATURE_LENGTH: usize = BANDS * ROWS;
+
+type Signature = [u64; SIGNATURE_LENGTH];
+
+/// Index of the stored advice texts, kept in sync by `IndexedStore`.
+///
+/// A poisoned lock is recovered from rather than reported, as for the search
+/// index.
+#[derive(Debug, Default)]
+pub struct DuplicateIndex {
+    inner: RwLock<Index>,
+}
+
+#[derive(Debug, Default)]
+struct Index {
+    docs: HashMap<i64, Doc>,
+    /// Ids of the advices with each normalised text hash.
+    exact: HashMap<u64, BTreeSet<i64>>,
+    /// Ids of the advices with each band hash, keyed by band number too.
+    bands: HashMap<(usize, u64), BTreeSet<i64>>,
+}
+
+#[derive(Debug)]
+struct Doc {
+    hash: u64,
+    signature: Signature,
+}
+
+/// A stored advice similar to a given text.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Match {
+    pub id: i64,
+    /// Estimated share of shingles in common, 1 for exact duplicates.
+    pub similarity: f64,
+    pub exact: bool,
+}
+
+/// Two stored advices that duplicate each other, the lower id first.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Pair {
+    pub ids: (i64, i64),
+    pub similarity: f64,
+    pub exact: bool,
+}
+
+impl DuplicateIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Indexes `advice`, replacing what was indexed for it before.
+    pub fn insert(&self, advice: &Advice) {
+        let mut index = self.inner.write().unwrap_or_else(PoisonError::into_inner);
+        index.remove(advice.id);
+
+        let normalized = normalize(&advice.advice);
+        if normalized.is_empty() {
+            return;
+        }
+        let doc = Doc {
+            hash: pick::hash(&normalized),
+            signature: signature(&normalized),
+        };
+
+        index.exact.entry(doc.hash).or_default().insert(advice.id);
+        for band in band_hashes(&doc.signature) {
+            index.bands.entry(band).or_default().insert(advice.id);
+        }
+        index.docs.insert(advice.id, doc);
+    }
+
+    pub fn remove(&self, id: i64) {
+        let mut index = self.inner.write().unwrap_or_else(PoisonError::into_inner);
+        index.remove(id);
+    }
+
+    /// Returns the stored advice most similar to `text`, if it is at least a
+    /// near duplicate. Advice `exclude` is ignored, so that an advice can be
+    /// checked against the others when it is replaced; new advices have no
+    /// id to exclude yet.
+    pub fn find(&self, exclude: Option<i64>, text: &str) -> Option<Match> {
+        let index = self.inner.read().unwrap_or_else(PoisonError::into_inner);
+        let normalized = normalize(text);
+        if normalized.is_empty() {
+            return None;
+        }
+
+        let hash = pick::hash(&normalized);
+        if let Some(&other) = index
+            .exact
+            .get(&hash)
+            .and_then(|ids| ids.iter().find(|&&other| Some(other) != exclude))
+        {
+            return Some(Match {
+                id: other,
+                similarity: 1.0,
+                exact: true,
+            });
+        }
+
+        let signature = signature(&normalized);
+        band_hashes(&signature)
+            .filter_map(|band| index.bands.get(&band))
+            .flatten()
+            .copied()
+            .filter(|&other| Some(other) != exclude)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .map(|other| Match {
+                id: other,
+                similarity: similarity(&signature, &index.docs[&other].signature),
+                exact: false,
+            })
+            .filter(|candidate| candidate.similarity >= NEAR_DUPLICATE_THRESHOLD)
+            // Prefer the lowest id among equally similar advices.
+            .max_by(|a, b| a.similarity.total_cmp(&b.similarity).then(b.id.cmp(&a.id)))
+    }
+
+    /// Every pair of stored advices that are exact or near duplicates, most
+    /// similar first.
+    pub fn pairs(&self) -> Vec<Pair> {
+        let index = self.inner.read().unwrap_or_else(PoisonError::into_inner);
+
+        let mut candidates = HashSet::new();
+        for ids in index.exact.values().chain(index.bands.values()) {
+            for (i, &a) in ids.iter().enumerate() {
+                for &b in ids.iter().skip(i + 1) {
+                    candidates.insert((a, b));
+                }
+            }
+        }
+
+        let mut pairs = candidates
+            .into_iter()
+            .filter_map(|(a, b)| {
+                let (doc_a, doc_b) = (&index.docs[&a], &index.docs[&b]);
+                let exact = doc_a.hash == doc_b.hash;
+                let similarity = if exact {
+                    1.0
+                } else {
+                    similarity(&doc_a.signature, &doc_b.signature)
+                };
+
+                if exact || similarity >= NEAR_DUPLICATE_THRESHOLD {
+                    Some(Pair {
+                        ids: (a, b),
+                        similarity,
+                        exact,
+                    })
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        pairs.sort_by(|a, b| {
+            b.similarity
+                .total_cmp(&a.similarity)
+                .then(a.ids.cmp(&b.ids))
+        });
+
+        pairs
+    }
+}
+
+impl Index {
+    fn remove(&mut self, id: i64) {
+        let doc = match self.docs.remove(&id) {
+            Some(doc) => doc,
+            None => return,
+        };
+
+        remove_id(&mut self.exact, doc.hash, id);
+        for band in band_hashes(&doc.signature) {
+            remove_id(&mut self.bands, band, id);
+        }
+    }
+}
+
+fn remove_id<K: Eq + std::hash::Hash>(map: &mut HashMap<K, BTreeSet<i64>>, key: K, id: i64) {
+    if let Some(ids) = map.get_mut(&key) {
+        ids.remove(&id);
+        if ids.is_empty() {
+            map.remove(&key);
+        }
+    }
+}
+
+/// The lowercased words of `text`, separated by single spaces.
+pub fn normalize(text: &str) -> String {
+    search::tokenize(text)
+        .map(|(token, _)| token)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// MinHash signature of the character shingles of a normalised text. Texts
+/// shorter than a shingle are a single shingle.
+fn signature(normalized: &str) -> Signature {
+    let chars = normalized.chars().collect::<Vec<_>>();
+    let shingles = chars
+        .windows(SHINGLE_LENGTH.min(chars.len()).max(1))
+        .map(|shingle| pick::hash(&shingle.iter().collect::<String>()))
+        .collect::<Vec<_>>();
+
+    let mut signature = [u64::MAX; SIGNATURE_LENGTH];
+    for (i, min) in signature.iter_mut().enumerate() {
+        let seed = (i as u64 + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        for &shingle in &shingles {
+            *min = (*min).min(pick::splitmix64(shingle ^ seed));
+        }
+    }
+
+    signature
+}
+
+fn band_hashes(signature: &Signature) -> impl Iterator<Item = (usize, u64)> + '_ {
+    signature.chunks(ROWS).enumerate().map(|(band, rows)| {
+        let hash = rows
+            .iter()
+            .fold(0, |hash: u64, &row| pick::splitmix64(hash ^ row));
+        (band, hash)
+    })
+}
+
+/// Share of equal hashes, which estimates the share of shingles in common.
+fn similarity(a: &Signature, b: &Signature) -> f64 {
+    let equal = a.iter().zip(b.iter()).filter(|(a, b)| a == b).count();
+    equal as f64 / SIGNATURE_LENGTH as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(advices: &[(i64, &str)]) -> DuplicateIndex {
+        let index = DuplicateIndex::new();
+        for &(id, text) in advices {
+            index.insert(&Advice::new(id, text, "user"));
+        }
+        index
+    }
+
+    #[test]
+    fn only_the_excluded_id_is_ignored() {
+        let index = index(&[(0, "Stay hydrated."), (1, "Call your mother.")]);
+
+        let found = index.find(None, "stay HYDRATED").unwrap();
+        assert_eq!((found.id, found.exact), (0, true));
+        assert_eq!(index.find(Some(0), "Stay hydrated."), None);
+        assert_eq!(
+            index.find(Some(1), "Stay hydrated.").map(|found| found.id),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn texts_without_words_are_not_duplicates() {
+        let index = index(&[(1, "!!!"), (2, "...")]);
+
+        assert_eq!(index.find(None, "???"), None);
+        assert_eq!(index.find(None, "!!!"), None);
+        assert!(index.pairs().is_empty());
+    }
+}
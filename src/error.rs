@@ -0,0 +1,159 @@
+use crate::{
+    rate_limit::{Class, RateLimit},
+    request_id,
+};
+use axum::{
+    body::{Bytes, Full},
+    http::{header, HeaderValue, Response, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use serde::Serialize;
+use std::{convert::Infallible, sync::PoisonError};
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Errors surfaced by handlers, rendered as a JSON body of the form
+/// `{"code": ..., "message": ..., "request_id": ...}`.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("{0}")]
+    BadRequest(String),
+    #[error("{0}")]
+    Unauthorized(String),
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("{0}")]
+    NotAcceptable(String),
+    #[error("{0}")]
+    UnsupportedMediaType(String),
+    #[error("{0}")]
+    Conflict(String),
+    #[error("{0}")]
+    PreconditionFailed(String),
+    #[error("too many {0} requests, retry in {} seconds", .1.retry_after)]
+    RateLimited(Class, RateLimit),
+    #[error("upstream request failed: {0}")]
+    Upstream(anyhow::Error),
+    #[error("request timed out")]
+    Timeout,
+    #[error("{0}")]
+    Unavailable(String),
+    #[error("advice not found")]
+    NotFound,
+    #[error("tag {0:?} not found")]
+    TagNotFound(String),
+    #[error("API key {0:?} not found")]
+    KeyNotFound(String),
+    #[error("lock poisoned")]
+    Poisoned,
+    #[error("storage error: {0}")]
+    Storage(#[from] rusqlite::Error),
+    #[error(transparent)]
+    Internal(#[from] anyhow::Error),
+}
+
+impl Error {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Error::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Error::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Error::Forbidden(_) => StatusCode::FORBIDDEN,
+            Error::NotAcceptable(_) => StatusCode::NOT_ACCEPTABLE,
+            Error::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Error::Conflict(_) => StatusCode::CONFLICT,
+            Error::PreconditionFailed(_) => StatusCode::PRECONDITION_FAILED,
+            Error::RateLimited(..) => StatusCode::TOO_MANY_REQUESTS,
+            Error::Upstream(_) => StatusCode::BAD_GATEWAY,
+            Error::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            Error::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Error::NotFound | Error::TagNotFound(_) | Error::KeyNotFound(_) => {
+                StatusCode::NOT_FOUND
+            }
+            Error::Poisoned | Error::Storage(_) | Error::Internal(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    /// Machine-readable error code for clients to match on.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::BadRequest(_) => "bad_request",
+            Error::Unauthorized(_) => "unauthorized",
+            Error::Forbidden(_) => "forbidden",
+            Error::NotAcceptable(_) => "not_acceptable",
+            Error::UnsupportedMediaType(_) => "unsupported_media_type",
+            Error::Conflict(_) => "conflict",
+            Error::PreconditionFailed(_) => "precondition_failed",
+            Error::RateLimited(..) => "rate_limited",
+            Error::Upstream(_) => "upstream_error",
+            Error::Timeout => "timeout",
+            Error::Unavailable(_) => "unavailable",
+            Error::NotFound | Error::TagNotFound(_) | Error::KeyNotFound(_) => "not_found",
+            Error::Poisoned => "lock_poisoned",
+            Error::Storage(_) => "storage_error",
+            Error::Internal(_) => "internal_error",
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(error: reqwest::Error) -> Self {
+        if error.is_timeout() {
+            Error::Timeout
+        } else {
+            Error::Upstream(error.into())
+        }
+    }
+}
+
+impl<T> From<PoisonError<T>> for Error {
+    fn from(_: PoisonError<T>) -> Self {
+        Error::Poisoned
+    }
+}
+
+impl From<tokio::task::JoinError> for Error {
+    fn from(error: tokio::task::JoinError) -> Self {
+        Error::Internal(error.into())
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    code: &'static str,
+    message: String,
+    request_id: Option<String>,
+}
+
+impl IntoResponse for Error {
+    type Body = Full<Bytes>;
+    type BodyError = Infallible;
+
+    fn into_response(self) -> Response<Self::Body> {
+        let status = self.status();
+        if status.is_server_error() {
+            tracing::error!("{:#}", self);
+        }
+
+        let body = ErrorBody {
+            code: self.code(),
+            message: self.to_string(),
+            request_id: request_id::current(),
+        };
+
+        let mut response = (status, Json(body)).into_response();
+        match self {
+            Error::Unauthorized(_) => {
+                response
+                    .headers_mut()
+                    .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            }
+            Error::RateLimited(_, limit) => limit.set_headers(response.headers_mut()),
+            _ => {}
+        }
+
+        response
+    }
+}
@@ -0,0 +1,60 @@
+use crate::{pick, Advice, Error, Result};
+use axum::http::{header, HeaderMap, HeaderValue};
+
+/// Strong entity tag of an advice: its version, which the store bumps on
+/// every change, and a hash of its JSON representation. Versions start over
+/// when an id is deleted and stored again, so the hash keeps a tag from ever
+/// standing for different content.
+pub fn of(advice: &Advice) -> HeaderValue {
+    let json = serde_json::to_string(advice).expect("advice serializes to JSON");
+    let tag = format!("\"{}-{:016x}\"", advice.version, pick::hash(&json));
+
+    HeaderValue::from_str(&tag).expect("entity tag is a valid header value")
+}
+
+/// Whether the request's `If-None-Match` header lists `etag`, in which case
+/// the client's cached copy is still fresh.
+pub fn none_match(headers: &HeaderMap, etag: &HeaderValue) -> bool {
+    let etag = match etag.to_str() {
+        Ok(etag) => etag,
+        Err(_) => return false,
+    };
+
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|candidate| candidate.trim())
+        // `If-None-Match` uses weak comparison.
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag)
+}
+
+/// Version the request's `If-Match` header requires the advice to be at,
+/// given its `current` state. The store then checks that version, so that
+/// changes made since `current` was read fail the precondition too.
+///
+/// `None` means any version will do, either because there is no `If-Match`
+/// header or because it is `*`. Tags other than the one of `current` fail
+/// the precondition straight away.
+pub fn if_match(headers: &HeaderMap, current: &Advice) -> Result<Option<u64>> {
+    let value = match headers.get(header::IF_MATCH) {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+
+    let value = value.to_str().unwrap_or_default().trim();
+    if value == "*" {
+        return Ok(None);
+    }
+
+    // `If-Match` uses strong comparison, so weak tags never match.
+    if value.as_bytes() == of(current).as_bytes() {
+        Ok(Some(current.version))
+    } else {
+        Err(Error::PreconditionFailed(format!(
+            "If-Match {:?} does not match",
+            value
+        )))
+    }
+}
@@ -0,0 +1,156 @@
+//! Readiness checks, served by `GET /readyz`.
+
+use crate::{provider::AdviceProvider, store::AdviceStore, Result};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+
+/// Deadline of the storage check, so that a hung database fails its check
+/// rather than the probe.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    Failed,
+}
+
+/// Result of checking one dependency.
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub status: Status,
+    /// Whether a failure makes the server not ready.
+    pub critical: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub duration_ms: u64,
+    /// Whether this is the result of an earlier check.
+    pub cached: bool,
+}
+
+/// Results of every check.
+#[derive(Debug, Clone, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: BTreeMap<&'static str, Check>,
+}
+
+/// Checks that the store responds and, optionally, that the provider does.
+///
+/// Only the store is critical: while upstream is down, stored advices are
+/// still served and new ones can come from the fallback, so taking the server
+/// out of rotation would only make things worse. Upstream is pinged at most
+/// once per `upstream_ttl`, so that probes don't add to its load.
+pub struct Health {
+    store: Arc<dyn AdviceStore>,
+    provider: Option<Arc<dyn AdviceProvider>>,
+    upstream_ttl: Duration,
+    upstream_timeout: Duration,
+    /// Last upstream check and when it was made. Held while pinging, so
+    /// concurrent probes ping once.
+    upstream: Mutex<Option<(Instant, Check)>>,
+}
+
+impl Health {
+    pub fn new(store: Arc<dyn AdviceStore>) -> Self {
+        Self {
+            store,
+            provider: None,
+            upstream_ttl: Duration::ZERO,
+            upstream_timeout: CHECK_TIMEOUT,
+            upstream: Mutex::new(None),
+        }
+    }
+
+    /// Also checks `provider`, reusing a result for `ttl`.
+    ///
+    /// `timeout` should let a whole provider call through, retries included,
+    /// so that a slow but working upstream passes the check and the outcome
+    /// of the call reaches the circuit breaker.
+    pub fn check_upstream(
+        mut self,
+        provider: Arc<dyn AdviceProvider>,
+        ttl: Duration,
+        timeout: Duration,
+    ) -> Self {
+        self.provider = Some(provider);
+        self.upstream_ttl = ttl;
+        self.upstream_timeout = timeout;
+        self
+    }
+
+    pub async fn readiness(&self) -> Readiness {
+        let mut checks = BTreeMap::new();
+        checks.insert(
+            "storage",
+            run(true, CHECK_TIMEOUT, async {
+                self.store.count(None).await.map(drop)
+            })
+            .await,
+        );
+        if let Some(check) = self.upstream().await {
+            checks.insert("upstream", check);
+        }
+
+        Readiness {
+            ready: checks
+                .values()
+                .all(|check| !check.critical || check.status == Status::Ok),
+            checks,
+        }
+    }
+
+    async fn upstream(&self) -> Option<Check> {
+        let provider = self.provider.as_ref()?;
+        let mut last = self.upstream.lock().await;
+        if let Some((checked_at, check)) = &*last {
+            if checked_at.elapsed() < self.upstream_ttl {
+                return Some(Check {
+                    cached: true,
+                    ..check.clone()
+                });
+            }
+        }
+
+        let check = run(false, self.upstream_timeout, async {
+            provider.random().await.map(drop)
+        })
+        .await;
+        if check.status == Status::Failed {
+            tracing::warn!(
+                "upstream check failed: {}",
+                check.error.as_deref().unwrap_or_default()
+            );
+        }
+        *last = Some((Instant::now(), check.clone()));
+
+        Some(check)
+    }
+}
+
+async fn run(critical: bool, timeout: Duration, check: impl Future<Output = Result<()>>) -> Check {
+    let started = Instant::now();
+    let error = match tokio::time::timeout(timeout, check).await {
+        Ok(Ok(())) => None,
+        Ok(Err(error)) => Some(error.to_string()),
+        Err(_) => Some(format!("timed out after {:?}", timeout)),
+    };
+
+    Check {
+        status: if error.is_none() {
+            Status::Ok
+        } else {
+            Status::Failed
+        },
+        critical,
+        error,
+        duration_ms: started.elapsed().as_millis() as u64,
+        cached: false,
+    }
+}
@@ -0,0 +1,18 @@
+pub mod advice;
+pub mod auth;
+pub mod config;
+pub mod dedup;
+pub mod error;
+pub mod etag;
+pub mod health;
+pub mod metrics;
+pub mod pick;
+pub mod provider;
+pub mod rate_limit;
+pub mod request_id;
+pub mod search;
+pub mod store;
+pub mod transfer;
+
+pub use advice::Advice;
+pub use error::{Error, Result};
@@ -0,0 +1,260 @@
+//! Prometheus metrics, served by `GET /metrics`.
+
+use crate::Error;
+use axum::http::{Method, Request, Response};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tower::{Layer, Service};
+
+/// Prefix of every metric name.
+const NAMESPACE: &str = "advices";
+
+/// Routes with parameters, as registered in `main`. Requests are labelled
+/// with their route rather than their path, so that ids don't make a new
+/// series each.
+const ROUTES: &[&str] = &[
+    "/advices/:id",
+    "/advices/:id/tags",
+    "/advices/:id/tags/:tag",
+    "/tags/:name",
+    "/admin/keys/:id",
+];
+
+/// Routes without parameters. They take precedence over `ROUTES`, as they do
+/// in the router.
+const STATIC_ROUTES: &[&str] = &[
+    "/",
+    "/healthz",
+    "/readyz",
+    "/metrics",
+    "/advices",
+    "/advices/random",
+    "/advices/daily",
+    "/advices/search",
+    "/advices/duplicates",
+    "/advices/batch",
+    "/advices/import",
+    "/advices/import-upstream",
+    "/advices/export",
+    "/tags",
+    "/admin/keys",
+];
+
+/// The metrics of the server, registered in a registry of their own.
+#[derive(Debug)]
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_duration: HistogramVec,
+    upstream_duration: HistogramVec,
+    upstream_errors: IntCounterVec,
+    timeouts: IntCounter,
+    advices: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new_custom(Some(NAMESPACE.to_owned()), None)?;
+
+        let requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests handled"),
+            &["method", "route", "status"],
+        )?;
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Time taken to handle HTTP requests",
+            ),
+            &["method", "route", "status"],
+        )?;
+        let upstream_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "upstream_request_duration_seconds",
+                "Time taken by calls to the advice provider, retries included",
+            ),
+            &["provider", "outcome"],
+        )?;
+        let upstream_errors = IntCounterVec::new(
+            Opts::new(
+                "upstream_errors_total",
+                "Failed calls to the advice provider, by error code",
+            ),
+            &["provider", "code"],
+        )?;
+        let timeouts = IntCounter::new(
+            "request_timeouts_total",
+            "Requests aborted for taking longer than the request timeout",
+        )?;
+        let advices = IntGauge::new("stored_advices", "Advices in the store")?;
+
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(request_duration.clone()))?;
+        registry.register(Box::new(upstream_duration.clone()))?;
+        registry.register(Box::new(upstream_errors.clone()))?;
+        registry.register(Box::new(timeouts.clone()))?;
+        registry.register(Box::new(advices.clone()))?;
+
+        Ok(Self {
+            registry,
+            requests,
+            request_duration,
+            upstream_duration,
+            upstream_errors,
+            timeouts,
+            advices,
+        })
+    }
+
+    /// Records a call to `provider` that took `elapsed`.
+    pub fn observe_upstream(&self, provider: &str, elapsed: Duration, error: Option<&Error>) {
+        let outcome = match error {
+            None => "ok",
+            Some(Error::Timeout) => "timeout",
+            Some(_) => "error",
+        };
+        self.upstream_duration
+            .with_label_values(&[provider, outcome])
+            .observe(elapsed.as_secs_f64());
+
+        if let Some(error) = error {
+            self.upstream_errors
+                .with_label_values(&[provider, error.code()])
+                .inc();
+        }
+    }
+
+    /// Counts a request that hit the request timeout.
+    pub fn inc_timeouts(&self) {
+        self.timeouts.inc();
+    }
+
+    pub fn set_stored_advices(&self, count: usize) {
+        self.advices.set(count as i64);
+    }
+
+    /// Renders every metric in the Prometheus text format, returning it with
+    /// its content type.
+    pub fn render(&self) -> prometheus::Result<(String, String)> {
+        let encoder = TextEncoder::new();
+        let mut buf = Vec::new();
+        encoder.encode(&self.registry.gather(), &mut buf)?;
+
+        let text =
+            String::from_utf8(buf).map_err(|error| prometheus::Error::Msg(error.to_string()))?;
+
+        Ok((text, encoder.format_type().to_owned()))
+    }
+
+    fn observe_request(&self, method: &str, route: &str, status: u16, elapsed: Duration) {
+        let status = status.to_string();
+        let labels = [method, route, status.as_str()];
+        self.requests.with_label_values(&labels).inc();
+        self.request_duration
+            .with_label_values(&labels)
+            .observe(elapsed.as_secs_f64());
+    }
+}
+
+/// The route `path` was served by, or `other` for paths no route matches.
+pub fn route(path: &str) -> &'static str {
+    if let Some(route) = STATIC_ROUTES.iter().find(|route| **route == path) {
+        return route;
+    }
+
+    let segments = path.split('/').collect::<Vec<_>>();
+    ROUTES
+        .iter()
+        .find(|route| {
+            let pattern = route.split('/').collect::<Vec<_>>();
+            pattern.len() == segments.len()
+                && pattern.iter().zip(&segments).all(|(pattern, segment)| {
+                    (pattern.starts_with(':') && !segment.is_empty()) || pattern == segment
+                })
+        })
+        .copied()
+        .unwrap_or("other")
+}
+
+/// The method of a request, or `other` for extension methods, which clients
+/// could otherwise use to make up series.
+fn method(method: &Method) -> &'static str {
+    match *method {
+        Method::GET => "GET",
+        Method::HEAD => "HEAD",
+        Method::POST => "POST",
+        Method::PUT => "PUT",
+        Method::PATCH => "PATCH",
+        Method::DELETE => "DELETE",
+        Method::OPTIONS => "OPTIONS",
+        _ => "other",
+    }
+}
+
+/// Counts and times every request by method, route and status.
+///
+/// Layered outside `handle_error`, so that requests rejected by middleware
+/// are counted with the status they were rejected with.
+#[derive(Debug, Clone)]
+pub struct MetricsLayer {
+    metrics: Arc<Metrics>,
+}
+
+impl MetricsLayer {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        Self { metrics }
+    }
+}
+
+impl<S> Layer<S> for MetricsLayer {
+    type Service = MetricsService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        MetricsService {
+            inner,
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MetricsService<S> {
+    inner: S,
+    metrics: Arc<Metrics>,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for MetricsService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let method = method(req.method());
+        let route = route(req.uri().path());
+        let metrics = self.metrics.clone();
+        let started = Instant::now();
+        let future = self.inner.call(req);
+
+        Box::pin(async move {
+            let res = future.await?;
+            metrics.observe_request(method, route, res.status().as_u16(), started.elapsed());
+            Ok(res)
+        })
+    }
+}
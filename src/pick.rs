@@ -0,0 +1,27 @@
+//! Deterministic picks, so that the same seed chooses the same advice on every
+//! replica and across restarts and releases.
+
+/// Maps `seed` to an index below `len`. Seeds spread evenly over the indices.
+///
+/// # Panics
+///
+/// Panics if `len` is zero.
+pub fn index(seed: u64, len: usize) -> usize {
+    assert!(len > 0, "cannot pick from nothing");
+    (splitmix64(seed) % len as u64) as usize
+}
+
+/// Stable 64-bit hash of a string, to derive seeds from keys such as dates.
+pub fn hash(key: &str) -> u64 {
+    key.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// SplitMix64 finaliser; scrambles nearby seeds into unrelated values.
+pub fn splitmix64(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
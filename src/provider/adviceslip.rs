@@ -0,0 +1,155 @@
+use super::{cache::ResponseCache, AdviceProvider, UpstreamClient};
+use crate::{Advice, Error, Result};
+use anyhow::anyhow;
+use async_trait::async_trait;
+use reqwest::Url;
+use serde::Deserialize;
+use std::time::Duration;
+
+/// Client for the public API at <https://api.adviceslip.com>.
+#[derive(Debug)]
+pub struct AdviceSlip {
+    client: UpstreamClient,
+    base_url: String,
+    cache: Option<ResponseCache<Response>>,
+}
+
+/// Body of every adviceslip endpoint. Which shape comes back depends on the
+/// endpoint and on whether anything was found, not on the status code: a
+/// missing slip is a `message` with a 200.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum Response {
+    Slip { slip: Advice },
+    Slips { slips: Vec<Advice> },
+    Message { message: Message },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Message {
+    /// `notice` when a search found nothing, `error` otherwise.
+    #[serde(rename = "type")]
+    kind: String,
+    text: String,
+}
+
+impl AdviceSlip {
+    pub const DEFAULT_URL: &'static str = "https://api.adviceslip.com";
+
+    pub fn new(base_url: impl Into<String>, client: UpstreamClient) -> Self {
+        Self {
+            client,
+            base_url: base_url.into().trim_end_matches('/').to_owned(),
+            cache: None,
+        }
+    }
+
+    /// Reuses advices by id and search results for `ttl`, and serves them
+    /// past it while upstream fails. Random advices are never cached. A zero
+    /// `ttl` disables the cache.
+    pub fn cache(mut self, ttl: Duration) -> Self {
+        self.cache = (!ttl.is_zero()).then(|| ResponseCache::new(ttl));
+        self
+    }
+
+    /// Calls the endpoint at `segments` below the base URL, until `deadline`
+    /// if given. Segments are percent-encoded, so they may hold arbitrary
+    /// search terms.
+    async fn get(&self, segments: &[&str], deadline: Option<Duration>) -> Result<Response> {
+        let mut url = Url::parse(&self.base_url).map_err(anyhow::Error::from)?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("{} cannot be a base URL", self.base_url))?
+            .pop_if_empty()
+            .extend(segments);
+
+        self.client.get_json(url.as_str(), deadline).await
+    }
+
+    /// Like `get`, going through the cache if there is one.
+    async fn get_cached(&self, segments: &[&str]) -> Result<Response> {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return self.get(segments, None).await,
+        };
+        let key = segments.join("/");
+        if let Some(response) = cache.get(&key) {
+            return Ok(response);
+        }
+
+        match self.get(segments, None).await {
+            Ok(response) => {
+                cache.insert(key, response.clone());
+                Ok(response)
+            }
+            Err(error @ (Error::Upstream(_) | Error::Unavailable(_) | Error::Timeout)) => {
+                match cache.stale(&key) {
+                    Some(response) => {
+                        tracing::debug!("serving stale {} after upstream failed: {}", key, error);
+                        Ok(response)
+                    }
+                    None => Err(error),
+                }
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    fn slip(&self, response: Response) -> Result<Advice> {
+        match response {
+            Response::Slip { slip } => Ok(self.with_source(slip)),
+            Response::Message { message } => Err(Error::Upstream(anyhow!(
+                "adviceslip returned no slip: {}",
+                message.text
+            ))),
+            Response::Slips { .. } => Err(Error::Upstream(anyhow!(
+                "adviceslip returned a list instead of a slip"
+            ))),
+        }
+    }
+
+    fn with_source(&self, mut advice: Advice) -> Advice {
+        advice.source = self.name().to_owned();
+        advice
+    }
+}
+
+#[async_trait]
+impl AdviceProvider for AdviceSlip {
+    fn name(&self) -> &'static str {
+        "adviceslip"
+    }
+
+    async fn random(&self) -> Result<Advice> {
+        self.slip(self.get(&["advice"], None).await?)
+    }
+
+    async fn random_within(&self, deadline: Duration) -> Result<Advice> {
+        self.slip(self.get(&["advice"], Some(deadline)).await?)
+    }
+
+    async fn by_id(&self, id: i64) -> Result<Option<Advice>> {
+        match self.get_cached(&["advice", &id.to_string()]).await? {
+            Response::Slip { slip } => Ok(Some(self.with_source(slip))),
+            Response::Message { .. } => Ok(None),
+            Response::Slips { .. } => Err(Error::Upstream(anyhow!(
+                "adviceslip returned a list instead of a slip"
+            ))),
+        }
+    }
+
+    async fn search(&self, query: &str) -> Result<Vec<Advice>> {
+        match self.get_cached(&["advice", "search", query]).await? {
+            Response::Slips { slips } => Ok(slips
+                .into_iter()
+                .map(|slip| self.with_source(slip))
+                .collect()),
+            Response::Message { message } if message.kind == "notice" => Ok(Vec::new()),
+            Response::Message { message } => Err(Error::Upstream(anyhow!(
+                "adviceslip search failed: {}",
+                message.text
+            ))),
+            // Not documented, but a single match is harmless to accept.
+            Response::Slip { slip } => Ok(vec![self.with_source(slip)]),
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use std::{
+    sync::{Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+/// Stops calling a failing upstream for a while, so requests fail fast
+/// instead of waiting on calls that are unlikely to succeed.
+///
+/// After `threshold` consecutive failures the breaker opens and rejects calls
+/// for `cooldown`. Then a single trial call is let through: its success
+/// closes the breaker, its failure opens it again.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum State {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// A trial call is in flight. Another one is allowed after `until`, as
+    /// the caller of the first may have given up on it.
+    HalfOpen {
+        until: Instant,
+    },
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            state: Mutex::new(State::Closed { failures: 0 }),
+        }
+    }
+
+    /// Whether a call may be made now. Allowed calls should be followed by
+    /// `success` or `failure`; a trial call that never is gets replaced once
+    /// the cooldown is over.
+    pub fn allow(&self) -> bool {
+        let mut state = self.state();
+        let now = Instant::now();
+        match *state {
+            State::Closed { .. } => true,
+            State::Open { until } | State::HalfOpen { until } if now < until => false,
+            State::Open { .. } | State::HalfOpen { .. } => {
+                *state = State::HalfOpen {
+                    until: now + self.cooldown,
+                };
+                true
+            }
+        }
+    }
+
+    pub fn success(&self) {
+        *self.state() = State::Closed { failures: 0 };
+    }
+
+    pub fn failure(&self) {
+        let mut state = self.state();
+        let failures = match *state {
+            State::Closed { failures } => failures + 1,
+            State::Open { .. } | State::HalfOpen { .. } => self.threshold,
+        };
+
+        *state = if failures >= self.threshold {
+            tracing::warn!("upstream circuit breaker open for {:?}", self.cooldown);
+            State::Open {
+                until: Instant::now() + self.cooldown,
+            }
+        } else {
+            State::Closed { failures }
+        };
+    }
+
+    /// Whether calls are currently rejected.
+    pub fn is_open(&self) -> bool {
+        match *self.state() {
+            State::Closed { .. } => false,
+            State::Open { until } | State::HalfOpen { until } => Instant::now() < until,
+        }
+    }
+
+    /// The state is plain data that is always left consistent, so a poisoned
+    /// lock is recovered from.
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+
+    const COOLDOWN: Duration = Duration::from_millis(50);
+
+    fn open(breaker: &CircuitBreaker) {
+        for _ in 0..3 {
+            assert!(breaker.allow());
+            breaker.failure();
+        }
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let breaker = CircuitBreaker::new(3, COOLDOWN);
+        for _ in 0..2 {
+            assert!(breaker.allow());
+            breaker.failure();
+        }
+        assert!(!breaker.is_open());
+
+        assert!(breaker.allow());
+        breaker.failure();
+        assert!(breaker.is_open());
+        assert!(!breaker.allow());
+    }
+
+    #[test]
+    fn success_resets_the_failure_count() {
+        let breaker = CircuitBreaker::new(3, COOLDOWN);
+        for _ in 0..2 {
+            breaker.failure();
+        }
+        breaker.success();
+        for _ in 0..2 {
+            breaker.failure();
+        }
+        assert!(!breaker.is_open());
+        assert!(breaker.allow());
+    }
+
+    #[test]
+    fn allows_a_single_trial_after_the_cooldown() {
+        let breaker = CircuitBreaker::new(3, COOLDOWN);
+        open(&breaker);
+
+        sleep(COOLDOWN);
+        assert!(!breaker.is_open());
+        assert!(breaker.allow());
+        assert!(breaker.is_open());
+        assert!(!breaker.allow());
+    }
+
+    #[test]
+    fn successful_trial_closes() {
+        let breaker = CircuitBreaker::new(3, COOLDOWN);
+        open(&breaker);
+
+        sleep(COOLDOWN);
+        assert!(breaker.allow());
+        breaker.success();
+        assert!(!breaker.is_open());
+        assert!(breaker.allow());
+        assert!(breaker.allow());
+    }
+
+    #[test]
+    fn failed_trial_opens_again() {
+        let breaker = CircuitBreaker::new(3, COOLDOWN);
+        open(&breaker);
+
+        sleep(COOLDOWN);
+        assert!(breaker.allow());
+        breaker.failure();
+        assert!(breaker.is_open());
+        assert!(!breaker.allow());
+    }
+
+    #[test]
+    fn abandoned_trial_is_replaced_after_the_cooldown() {
+        let breaker = CircuitBreaker::new(3, COOLDOWN);
+        open(&breaker);
+
+        sleep(COOLDOWN);
+        assert!(breaker.allow());
+        sleep(COOLDOWN);
+        assert!(breaker.allow());
+    }
+}
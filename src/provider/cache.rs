@@ -0,0 +1,96 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+/// Upstream responses by URL, reused for `ttl` and kept past it to stand in
+/// for upstream while it fails.
+#[derive(Debug)]
+pub struct ResponseCache<T> {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, (Instant, T)>>,
+}
+
+impl<T: Clone> ResponseCache<T> {
+    /// Most responses kept. Searches are keyed by arbitrary terms, so the
+    /// cache would otherwise grow with every distinct query.
+    pub const MAX_ENTRIES: usize = 1_000;
+
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The response for `key` if it was stored less than `ttl` ago.
+    pub fn get(&self, key: &str) -> Option<T> {
+        match self.entries().get(key) {
+            Some((stored, value)) if stored.elapsed() < self.ttl => Some(value.clone()),
+            _ => None,
+        }
+    }
+
+    /// The response for `key`, however old.
+    pub fn stale(&self, key: &str) -> Option<T> {
+        self.entries().get(key).map(|(_, value)| value.clone())
+    }
+
+    /// Stores `value` for `key`, making room by dropping expired responses
+    /// first and the oldest one if that is not enough.
+    pub fn insert(&self, key: impl Into<String>, value: T) {
+        let key = key.into();
+        let mut entries = self.entries();
+        if entries.len() >= Self::MAX_ENTRIES && !entries.contains_key(&key) {
+            let ttl = self.ttl;
+            entries.retain(|_, (stored, _)| stored.elapsed() < ttl);
+            if entries.len() >= Self::MAX_ENTRIES {
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, (stored, _))| *stored)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+        entries.insert(key, (Instant::now(), value));
+    }
+
+    fn entries(&self) -> MutexGuard<'_, HashMap<String, (Instant, T)>> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expired_responses_are_only_served_stale() {
+        let cache = ResponseCache::new(Duration::from_millis(20));
+        cache.insert("a", 1);
+        assert_eq!(cache.get("a"), Some(1));
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(cache.get("a"), None);
+        assert_eq!(cache.stale("a"), Some(1));
+        assert_eq!(cache.stale("b"), None);
+    }
+
+    #[test]
+    fn inserting_into_a_full_cache_drops_the_oldest_response() {
+        let cache = ResponseCache::new(Duration::from_secs(60));
+        for i in 0..ResponseCache::<usize>::MAX_ENTRIES {
+            cache.insert(i.to_string(), i);
+        }
+        cache.insert("0", 0);
+        cache.insert("new", 0);
+
+        assert_eq!(cache.entries().len(), ResponseCache::<usize>::MAX_ENTRIES);
+        assert_eq!(cache.stale("0"), Some(0));
+        assert_eq!(cache.stale("1"), None);
+        assert_eq!(cache.stale("new"), Some(0));
+    }
+}
@@ -0,0 +1,239 @@
+use super::CircuitBreaker;
+use crate::{config::UpstreamConfig, Error, Result};
+use rand::Rng;
+use serde::de::DeserializeOwned;
+use std::time::{Duration, Instant};
+
+/// HTTP client shared by all calls to an upstream API.
+///
+/// Calls failing with a network error or a 5xx status are retried with
+/// exponential backoff and jitter. Calls that still fail count towards the
+/// circuit breaker, and so do calls dropped after an attempt failed or took
+/// longer than its timeout, as when the request they serve times out. While
+/// the breaker is open, calls fail at once with `Error::Unavailable`.
+///
+/// Calls may be given a deadline, retries included, as when a fallback is
+/// served past it, so that they fail and count towards the breaker before.
+#[derive(Debug)]
+pub struct UpstreamClient {
+    client: reqwest::Client,
+    timeout: Duration,
+    retries: u32,
+    backoff: Duration,
+    max_backoff: Duration,
+    breaker: CircuitBreaker,
+}
+
+impl UpstreamClient {
+    pub fn new(config: &UpstreamConfig) -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder()
+            .connect_timeout(config.connect_timeout())
+            .timeout(config.timeout())
+            .user_agent(concat!("advices-api/", env!("CARGO_PKG_VERSION")))
+            .build()?;
+
+        Ok(Self {
+            client,
+            timeout: config.timeout(),
+            retries: config.retries,
+            backoff: Duration::from_millis(config.backoff_ms),
+            max_backoff: Duration::from_millis(config.max_backoff_ms),
+            breaker: CircuitBreaker::new(config.breaker_threshold, config.breaker_cooldown()),
+        })
+    }
+
+    /// Fetches `url` and decodes its JSON body, giving up on retries and
+    /// cutting the last attempt short at `deadline`.
+    pub async fn get_json<T: DeserializeOwned>(
+        &self,
+        url: &str,
+        deadline: Option<Duration>,
+    ) -> Result<T> {
+        if !self.breaker.allow() {
+            return Err(Error::Unavailable(
+                "upstream is failing, not calling it for now".to_owned(),
+            ));
+        }
+
+        let mut outcome = Outcome::new(&self.breaker);
+        let started = Instant::now();
+        let mut attempt = 0;
+        let result = loop {
+            let timeout = match remaining(deadline, started) {
+                Some(remaining) => remaining.min(self.timeout),
+                None => self.timeout,
+            };
+            outcome.attempt(timeout);
+            match self.try_get_json(url, timeout).await {
+                Err(error) if attempt < self.retries && is_transient(&error) => {
+                    outcome.failed = true;
+                    let delay = self.backoff(attempt);
+                    if matches!(remaining(deadline, started), Some(remaining) if remaining <= delay)
+                    {
+                        break Err(error);
+                    }
+                    tracing::debug!("retrying {} in {:?}: {}", url, delay, error);
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => break result,
+            }
+        };
+
+        // Other errors, such as a 404 or an unexpected body, show that
+        // upstream is reachable.
+        match &result {
+            Err(error) if is_transient(error) => outcome.failure(),
+            _ => outcome.success(),
+        }
+
+        result.map_err(Error::from)
+    }
+
+    /// Whether calls are currently rejected by the circuit breaker.
+    pub fn is_open(&self) -> bool {
+        self.breaker.is_open()
+    }
+
+    async fn try_get_json<T: DeserializeOwned>(
+        &self,
+        url: &str,
+        timeout: Duration,
+    ) -> reqwest::Result<T> {
+        self.client
+            .get(url)
+            .timeout(timeout)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+    }
+
+    /// Delay before retry `attempt`, counted from 0: doubled on every retry
+    /// up to `max_backoff`, then randomly shortened by up to half so that
+    /// clients don't retry in lockstep.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self
+            .backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff);
+
+        delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+}
+
+/// Reports a call to the breaker at most once.
+///
+/// A call dropped before reporting counts as a failure if an attempt already
+/// failed, or if the current one outlived its timeout, so that an upstream
+/// that hangs until the caller gives up opens the breaker too. Other drops,
+/// such as clients disconnecting, say nothing about upstream and are not
+/// reported.
+struct Outcome<'a> {
+    breaker: &'a CircuitBreaker,
+    reported: bool,
+    /// Whether an earlier attempt failed in a way that counts.
+    failed: bool,
+    /// When the current attempt started, and its timeout.
+    attempt: Option<(Instant, Duration)>,
+}
+
+impl<'a> Outcome<'a> {
+    fn new(breaker: &'a CircuitBreaker) -> Self {
+        Self {
+            breaker,
+            reported: false,
+            failed: false,
+            attempt: None,
+        }
+    }
+
+    /// Notes that an attempt with `timeout` starts now.
+    fn attempt(&mut self, timeout: Duration) {
+        self.attempt = Some((Instant::now(), timeout));
+    }
+
+    fn success(mut self) {
+        self.reported = true;
+        self.breaker.success();
+    }
+
+    fn failure(mut self) {
+        self.reported = true;
+        self.breaker.failure();
+    }
+}
+
+impl Drop for Outcome<'_> {
+    fn drop(&mut self) {
+        let overdue =
+            matches!(self.attempt, Some((started, timeout)) if started.elapsed() >= timeout);
+        if !self.reported && (self.failed || overdue) {
+            self.breaker.failure();
+        }
+    }
+}
+
+/// Time left before `deadline` for a call started at `started`, if it has one.
+fn remaining(deadline: Option<Duration>, started: Instant) -> Option<Duration> {
+    deadline.map(|deadline| deadline.saturating_sub(started.elapsed()))
+}
+
+/// Whether a call failed in a way that a retry may fix.
+fn is_transient(error: &reqwest::Error) -> bool {
+    match error.status() {
+        Some(status) => status.is_server_error(),
+        None => error.is_connect() || error.is_timeout() || error.is_request() || error.is_body(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker() -> CircuitBreaker {
+        CircuitBreaker::new(1, Duration::from_secs(60))
+    }
+
+    #[test]
+    fn drops_within_the_timeout_are_not_reported() {
+        let breaker = breaker();
+        let mut outcome = Outcome::new(&breaker);
+        outcome.attempt(Duration::from_secs(60));
+        drop(outcome);
+        assert!(!breaker.is_open());
+
+        drop(Outcome::new(&breaker));
+        assert!(!breaker.is_open());
+    }
+
+    #[test]
+    fn drops_past_the_timeout_are_failures() {
+        let breaker = breaker();
+        let mut outcome = Outcome::new(&breaker);
+        outcome.attempt(Duration::ZERO);
+        drop(outcome);
+        assert!(breaker.is_open());
+    }
+
+    #[test]
+    fn drops_after_a_failed_attempt_are_failures() {
+        let breaker = breaker();
+        let mut outcome = Outcome::new(&breaker);
+        outcome.attempt(Duration::from_secs(60));
+        outcome.failed = true;
+        outcome.attempt(Duration::from_secs(60));
+        drop(outcome);
+        assert!(breaker.is_open());
+    }
+
+    #[test]
+    fn reported_outcomes_are_not_reported_again() {
+        let breaker = breaker();
+        let mut outcome = Outcome::new(&breaker);
+        outcome.attempt(Duration::ZERO);
+        outcome.success();
+        assert!(!breaker.is_open());
+    }
+}
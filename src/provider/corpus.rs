@@ -0,0 +1,107 @@
+use super::AdviceProvider;
+use crate::{Advice, Result};
+use anyhow::{bail, Context};
+use async_trait::async_trait;
+use rand::seq::SliceRandom;
+use std::{fs, path::Path};
+
+const BUNDLED: &str = include_str!("../../corpus/advices.jsonl");
+
+/// Serves advices from a local corpus instead of the network.
+///
+/// The corpus is either a JSON array of advices or JSON Lines with one advice
+/// per line, picked by the `.jsonl` extension.
+#[derive(Debug, Clone)]
+pub struct Corpus {
+    advices: Vec<Advice>,
+}
+
+impl Corpus {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("failed to read corpus {}", path.display()))?;
+
+        let advices = if path.extension() == Some("jsonl".as_ref()) {
+            Self::parse_lines(&content)
+        } else {
+            serde_json::from_str(&content).map_err(Into::into)
+        }
+        .with_context(|| format!("failed to parse corpus {}", path.display()))?;
+
+        Self::new(advices)
+    }
+
+    /// The corpus compiled into the binary, from `corpus/advices.jsonl`.
+    pub fn bundled() -> Self {
+        let advices = Self::parse_lines(BUNDLED).expect("bundled corpus is valid");
+        Self::new(advices).expect("bundled corpus is not empty")
+    }
+
+    pub fn new(advices: Vec<Advice>) -> anyhow::Result<Self> {
+        if advices.is_empty() {
+            bail!("corpus has no advices");
+        }
+
+        Ok(Self { advices })
+    }
+
+    pub fn advices(&self) -> &[Advice] {
+        &self.advices
+    }
+
+    fn with_source(&self, advice: &Advice) -> Advice {
+        let mut advice = advice.clone();
+        if advice.source.is_empty() {
+            advice.source = self.name().to_owned();
+        }
+
+        advice
+    }
+
+    fn parse_lines(content: &str) -> anyhow::Result<Vec<Advice>> {
+        content
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| {
+                serde_json::from_str(line)
+                    .with_context(|| format!("invalid advice on line {}", i + 1))
+            })
+            .collect()
+    }
+}
+
+#[async_trait]
+impl AdviceProvider for Corpus {
+    fn name(&self) -> &'static str {
+        "corpus"
+    }
+
+    async fn random(&self) -> Result<Advice> {
+        let advice = self
+            .advices
+            .choose(&mut rand::thread_rng())
+            .expect("corpus is never empty");
+
+        Ok(self.with_source(advice))
+    }
+
+    async fn by_id(&self, id: i64) -> Result<Option<Advice>> {
+        let advice = self.advices.iter().find(|advice| advice.id == id);
+
+        Ok(advice.map(|advice| self.with_source(advice)))
+    }
+
+    async fn search(&self, query: &str) -> Result<Vec<Advice>> {
+        let query = query.to_lowercase();
+        let advices = self
+            .advices
+            .iter()
+            .filter(|advice| advice.advice.to_lowercase().contains(&query))
+            .map(|advice| self.with_source(advice))
+            .collect();
+
+        Ok(advices)
+    }
+}
@@ -0,0 +1,73 @@
+use super::Corpus;
+use crate::{store::AdviceStore, Advice, Error, Result};
+use rand::{seq::SliceRandom, Rng};
+use std::time::Duration;
+
+/// Stands in for the provider when it fails or is too slow to answer.
+#[derive(Debug)]
+pub struct Fallback {
+    corpus: Corpus,
+    deadline: Duration,
+}
+
+/// Advice served in place of a fresh one.
+#[derive(Debug)]
+pub struct FallbackAdvice {
+    pub advice: Advice,
+    /// Whether the advice was added to the store, rather than picked from it.
+    pub created: bool,
+}
+
+impl Fallback {
+    /// Source of advices served by the fallback.
+    pub const SOURCE: &'static str = "fallback";
+
+    /// `deadline` is how long the provider gets before the fallback is used.
+    pub fn new(corpus: Corpus, deadline: Duration) -> Self {
+        Self { corpus, deadline }
+    }
+
+    pub fn deadline(&self) -> Duration {
+        self.deadline
+    }
+
+    /// Stores a corpus advice that is not stored yet, as created by
+    /// `created_by`. Once every one is, picks a stored advice instead.
+    pub async fn advice(
+        &self,
+        store: &dyn AdviceStore,
+        created_by: Option<String>,
+    ) -> Result<FallbackAdvice> {
+        let mut candidates = self.corpus.advices().iter().collect::<Vec<_>>();
+        candidates.shuffle(&mut rand::thread_rng());
+
+        for candidate in candidates {
+            let mut advice = Advice::new(0, candidate.advice.clone(), Self::SOURCE);
+            advice.created_by = created_by.clone();
+            match store.insert_local(advice).await {
+                Ok(advice) => {
+                    return Ok(FallbackAdvice {
+                        advice,
+                        created: true,
+                    })
+                }
+                // Stored before, try another one.
+                Err(Error::Conflict(_)) => {}
+                Err(error) => return Err(error),
+            }
+        }
+
+        let count = store.count(None).await?;
+        if count == 0 {
+            return Err(Error::NotFound);
+        }
+        let index = rand::thread_rng().gen_range(0..count);
+        let mut advice = store.nth(None, index).await?.ok_or(Error::NotFound)?;
+        advice.source = Self::SOURCE.to_owned();
+
+        Ok(FallbackAdvice {
+            advice,
+            created: false,
+        })
+    }
+}
@@ -0,0 +1,49 @@
+use crate::{Advice, Error, Result};
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::time::Duration;
+
+mod adviceslip;
+mod breaker;
+mod cache;
+mod client;
+mod corpus;
+mod fallback;
+
+pub use adviceslip::AdviceSlip;
+pub use breaker::CircuitBreaker;
+pub use client::UpstreamClient;
+pub use corpus::Corpus;
+pub use fallback::{Fallback, FallbackAdvice};
+
+/// Query of `POST /advices/batch`. `RateLimitLayer` decodes it too, to charge
+/// a batch once per advice requested.
+#[derive(Debug, Deserialize)]
+pub struct BatchParams {
+    pub count: usize,
+}
+
+/// Source of fresh advices for `POST /advices`.
+#[async_trait]
+pub trait AdviceProvider: Send + Sync {
+    /// Short identifier of the provider, used in logs.
+    fn name(&self) -> &'static str;
+
+    /// Returns a random advice.
+    async fn random(&self) -> Result<Advice>;
+
+    /// Returns a random advice, failing with `Error::Timeout` past
+    /// `deadline`. Providers calling upstream should stop their own attempts
+    /// at the deadline, so that they see the failure.
+    async fn random_within(&self, deadline: Duration) -> Result<Advice> {
+        tokio::time::timeout(deadline, self.random())
+            .await
+            .unwrap_or(Err(Error::Timeout))
+    }
+
+    /// Returns the advice with the provider's id `id`, if there is one.
+    async fn by_id(&self, id: i64) -> Result<Option<Advice>>;
+
+    /// Returns the advices whose text contains `query`.
+    async fn search(&self, query: &str) -> Result<Vec<Advice>>;
+}
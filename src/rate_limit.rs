@@ -0,0 +1,378 @@
+//! Per-client rate limiting with token buckets.
+//!
+//! Every client has a bucket per class of routes, holding up to `burst`
+//! tokens and refilled at a steady rate. A request takes a token, or more for
+//! batches; when the bucket runs dry it is rejected with
+//! `Error::RateLimited` until enough tokens are back. Failed authentications
+//! take tokens from a bucket per IP address instead, checked by `AuthLayer`.
+
+use crate::{auth::Principal, provider::BatchParams, Error, Result};
+use axum::{
+    extract::ConnectInfo,
+    http::{HeaderMap, HeaderValue, Method, Request, Response},
+};
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    fmt,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, Mutex, PoisonError},
+    task::{Context, Poll},
+    time::Instant,
+};
+use tower::{BoxError, Layer, Service};
+
+/// Most buckets kept, to bound the memory used by clients that came and went,
+/// or that make up addresses.
+const MAX_BUCKETS: usize = 10_000;
+/// Buckets kept when evicting, so that evicting happens at most once every
+/// `MAX_BUCKETS - EVICT_TO` new clients.
+const EVICT_TO: usize = MAX_BUCKETS * 3 / 4;
+
+/// Which limit a request counts against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Class {
+    /// Routes that call upstream, so that a single client cannot get us
+    /// throttled there.
+    Upstream,
+    /// Every other route.
+    Read,
+    /// Requests with credentials that failed to authenticate, so that keys
+    /// cannot be guessed at the rate the other limits allow.
+    AuthFailure,
+}
+
+impl fmt::Display for Class {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Class::Upstream => "upstream",
+            Class::Read => "read",
+            Class::AuthFailure => "failed authentication",
+        })
+    }
+}
+
+/// Size and refill rate of a bucket.
+#[derive(Debug, Clone, Copy)]
+pub struct Limit {
+    pub burst: u32,
+    pub per_minute: u32,
+}
+
+impl Limit {
+    fn refill_per_second(&self) -> f64 {
+        f64::from(self.per_minute) / 60.0
+    }
+
+    /// Time to refill `tokens`, rounded up to whole seconds as headers use.
+    fn time_to_refill(&self, tokens: f64) -> u64 {
+        (tokens.max(0.0) / self.refill_per_second()).ceil() as u64
+    }
+}
+
+/// State of a client's bucket after a request, sent back as `RateLimit-*`
+/// headers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub limit: u32,
+    pub remaining: u32,
+    /// Seconds until the bucket is full again.
+    pub reset: u64,
+    /// Seconds until the rejected request could go through.
+    pub retry_after: u64,
+}
+
+impl RateLimit {
+    /// Adds the `RateLimit-Limit`, `RateLimit-Remaining` and
+    /// `RateLimit-Reset` headers, and `Retry-After` for rejected requests.
+    pub fn set_headers(&self, headers: &mut HeaderMap) {
+        headers.insert("ratelimit-limit", HeaderValue::from(self.limit));
+        headers.insert("ratelimit-remaining", HeaderValue::from(self.remaining));
+        headers.insert("ratelimit-reset", HeaderValue::from(self.reset));
+        if self.retry_after > 0 {
+            headers.insert("retry-after", HeaderValue::from(self.retry_after));
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Limits requests per client, identified by their `Principal` when
+/// `AuthLayer` set one and by their IP address otherwise, so it must be
+/// layered inside `AuthLayer`. Give `AuthLayer` a clone of it too, to limit
+/// failed authentications, which never get this far.
+///
+/// Rejected requests fail with `Error::RateLimited` as the service error, for
+/// `handle_error` to render; accepted ones get `RateLimit-*` headers.
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    read: Limit,
+    upstream: Limit,
+    auth_failure: Limit,
+    enforce: bool,
+    trust_forwarded_for: bool,
+    buckets: Arc<Mutex<HashMap<(Class, String), Bucket>>>,
+}
+
+impl RateLimitLayer {
+    pub fn new(read: Limit, upstream: Limit, auth_failure: Limit) -> Self {
+        Self {
+            read,
+            upstream,
+            auth_failure,
+            enforce: true,
+            trust_forwarded_for: false,
+            buckets: Arc::default(),
+        }
+    }
+
+    /// Whether requests are limited at all.
+    pub fn enforce(mut self, enforce: bool) -> Self {
+        self.enforce = enforce;
+        self
+    }
+
+    /// Takes the client IP address from the last `X-Forwarded-For` entry, as
+    /// added by the reverse proxy in front of us. Only enable this behind a
+    /// proxy, as clients can send any value.
+    pub fn trust_forwarded_for(mut self, trust: bool) -> Self {
+        self.trust_forwarded_for = trust;
+        self
+    }
+
+    fn limit(&self, class: Class) -> Limit {
+        match class {
+            Class::Upstream => self.upstream,
+            Class::Read => self.read,
+            Class::AuthFailure => self.auth_failure,
+        }
+    }
+
+    /// Fails with `Error::RateLimited` if the IP address `req` comes from has
+    /// no failed authentications left, without using one up.
+    pub fn check_auth_failures<B>(&self, req: &Request<B>) -> Result<()> {
+        if !self.enforce {
+            return Ok(());
+        }
+        self.spend(Class::AuthFailure, self.ip(req), 1.0, false)
+            .map(drop)
+    }
+
+    /// Records a failed authentication for the IP address `req` comes from.
+    pub fn charge_auth_failure<B>(&self, req: &Request<B>) {
+        if self.enforce {
+            // Rejecting this request is up to the next check.
+            let _ = self.spend(Class::AuthFailure, self.ip(req), 1.0, true);
+        }
+    }
+
+    /// Takes `cost` tokens from the bucket of `client` for `class`.
+    fn take(&self, class: Class, client: String, cost: u32) -> Result<RateLimit> {
+        let limit = self.limit(class);
+        // Such a batch could never go through, and letting it through for
+        // less would defeat the limit.
+        if cost > limit.burst {
+            return Err(Error::BadRequest(format!(
+                "batches may request at most {} advices, the {} rate limit burst",
+                limit.burst, class
+            )));
+        }
+        self.spend(class, client, f64::from(cost.max(1)), true)
+    }
+
+    /// Checks that the bucket of `client` for `class` holds `cost` tokens, and
+    /// takes them if `take` is set.
+    fn spend(&self, class: Class, client: String, cost: f64, take: bool) -> Result<RateLimit> {
+        let limit = self.limit(class);
+        let now = Instant::now();
+
+        // The map is plain data that is always left consistent, so a
+        // poisoned lock is recovered from.
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        if buckets.len() >= MAX_BUCKETS {
+            self.evict(&mut buckets, now);
+        }
+
+        let bucket = buckets.entry((class, client)).or_insert(Bucket {
+            tokens: f64::from(limit.burst),
+            updated: now,
+        });
+        bucket.tokens = refilled(bucket, limit, now);
+        bucket.updated = now;
+
+        let accepted = bucket.tokens >= cost;
+        if accepted && take {
+            bucket.tokens -= cost;
+        }
+
+        let state = RateLimit {
+            limit: limit.burst,
+            remaining: bucket.tokens.floor() as u32,
+            reset: limit.time_to_refill(f64::from(limit.burst) - bucket.tokens),
+            retry_after: if accepted {
+                0
+            } else {
+                limit.time_to_refill(cost - bucket.tokens).max(1)
+            },
+        };
+
+        if accepted {
+            Ok(state)
+        } else {
+            Err(Error::RateLimited(class, state))
+        }
+    }
+
+    /// Drops full buckets, which are the same as new ones, then the least
+    /// recently used ones until `EVICT_TO` are left.
+    fn evict(&self, buckets: &mut HashMap<(Class, String), Bucket>, now: Instant) {
+        buckets.retain(|(class, _), bucket| {
+            let limit = self.limit(*class);
+            refilled(bucket, limit, now) < f64::from(limit.burst)
+        });
+
+        if buckets.len() > EVICT_TO {
+            let mut keys = buckets
+                .iter()
+                .map(|(key, bucket)| (bucket.updated, key.clone()))
+                .collect::<Vec<_>>();
+            keys.sort_unstable_by_key(|(updated, _)| *updated);
+            for (_, key) in keys.into_iter().take(buckets.len() - EVICT_TO) {
+                buckets.remove(&key);
+            }
+        }
+    }
+
+    /// Identifies who sent the request.
+    fn client<B>(&self, req: &Request<B>) -> String {
+        match req.extensions().get::<Principal>() {
+            Some(principal) => format!("principal:{}", principal.id),
+            None => self.ip(req),
+        }
+    }
+
+    /// Identifies the IP address the request comes from.
+    fn ip<B>(&self, req: &Request<B>) -> String {
+        let forwarded = if self.trust_forwarded_for {
+            req.headers()
+                .get("x-forwarded-for")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.rsplit(',').next())
+                .and_then(|ip| ip.trim().parse::<IpAddr>().ok())
+        } else {
+            None
+        };
+        let ip = forwarded.or_else(|| {
+            req.extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip())
+        });
+
+        match ip {
+            Some(ip) => format!("ip:{}", ip),
+            None => "unknown".to_owned(),
+        }
+    }
+}
+
+/// Tokens in `bucket` at `now`, refilled since it was last updated.
+fn refilled(bucket: &Bucket, limit: Limit, now: Instant) -> f64 {
+    let elapsed = now.saturating_duration_since(bucket.updated);
+    (bucket.tokens + elapsed.as_secs_f64() * limit.refill_per_second()).min(f64::from(limit.burst))
+}
+
+/// Paths that are never limited, so that load balancer probes are not
+/// mistaken for a failing server.
+const EXEMPT_PATHS: &[&str] = &["/healthz", "/readyz"];
+
+/// The class of a request and how many tokens it takes: one, except for
+/// batches, which take one per advice requested. `None` for exempt paths.
+fn classify<B>(req: &Request<B>) -> Option<(Class, u32)> {
+    if EXEMPT_PATHS.contains(&req.uri().path()) {
+        return None;
+    }
+
+    let class = match (req.method(), req.uri().path()) {
+        // With a JSON body this stores the advice without calling upstream,
+        // but counting every creation is safer than second-guessing the body
+        // extractor.
+        (&Method::POST, "/advices") | (&Method::POST, "/advices/import-upstream") => {
+            (Class::Upstream, 1)
+        }
+        (&Method::POST, "/advices/batch") => {
+            // Decoded as the handler does, so that an encoded query costs the
+            // same. The handler rejects queries that don't decode.
+            let count =
+                serde_urlencoded::from_str::<BatchParams>(req.uri().query().unwrap_or_default())
+                    .map_or(1, |params| u32::try_from(params.count).unwrap_or(u32::MAX));
+            (Class::Upstream, count)
+        }
+        _ => (Class::Read, 1),
+    };
+
+    Some(class)
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimitService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimitService {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimitService<S> {
+    inner: S,
+    layer: RateLimitLayer,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for RateLimitService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+    ReqBody: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let state = match classify(&req).filter(|_| self.layer.enforce) {
+            Some((class, cost)) => {
+                let client = self.layer.client(&req);
+                match self.layer.take(class, client, cost) {
+                    Ok(state) => Some(state),
+                    Err(error) => return Box::pin(async move { Err(error.into()) }),
+                }
+            }
+            None => None,
+        };
+
+        // Call the service that was polled ready, leaving a fresh clone behind.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        Box::pin(async move {
+            let mut res = inner.call(req).await.map_err(Into::into)?;
+            if let Some(state) = state {
+                state.set_headers(res.headers_mut());
+            }
+            Ok(res)
+        })
+    }
+}
@@ -0,0 +1,73 @@
+use axum::http::{header::HeaderName, HeaderValue, Request, Response};
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tower::{Layer, Service};
+
+/// Header used to receive and return the request id.
+pub const X_REQUEST_ID: &str = "x-request-id";
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// Returns the id of the request currently being handled, if any.
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+/// Tags every request with an id, reusing the client's `x-request-id` when it
+/// sends a sane one, and echoes it back on the response.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RequestIdLayer;
+
+impl<S> Layer<S> for RequestIdLayer {
+    type Service = RequestIdService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequestIdService { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RequestIdService<S> {
+    inner: S,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for RequestIdService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let id = req
+            .headers()
+            .get(X_REQUEST_ID)
+            .and_then(|value| value.to_str().ok())
+            .filter(|id| !id.is_empty() && id.len() <= 128)
+            .map(ToOwned::to_owned)
+            .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()));
+
+        let header = HeaderValue::from_str(&id).ok();
+        let future = self.inner.call(req);
+
+        Box::pin(REQUEST_ID.scope(id, async move {
+            let mut res = future.await?;
+            if let Some(header) = header {
+                res.headers_mut()
+                    .insert(HeaderName::from_static(X_REQUEST_ID), header);
+            }
+            Ok(res)
+        }))
+    }
+}
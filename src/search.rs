@@ -0,0 +1,199 @@
+//! Full-text search over advice text.
+//!
+//! Text is split into lowercased alphanumeric tokens. The inverted index maps
+//! every token to the advices containing it, and hits are ranked with BM25.
+
+use crate::Advice;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    ops::Range,
+    sync::{PoisonError, RwLock},
+};
+
+/// BM25 term frequency saturation.
+const K1: f64 = 1.2;
+/// BM25 document length normalisation.
+const B: f64 = 0.75;
+
+/// Inverted index of advice text, shared between the store that keeps it up
+/// to date and the search handler.
+///
+/// A poisoned lock is recovered from rather than reported: the index holds no
+/// data of its own and a stale entry only affects ranking.
+#[derive(Debug, Default)]
+pub struct SearchIndex {
+    inner: RwLock<Index>,
+}
+
+#[derive(Debug, Default)]
+struct Index {
+    /// Advice ids containing each token, with the number of occurrences.
+    postings: HashMap<String, HashMap<i64, u32>>,
+    /// Distinct tokens and token count of every indexed advice.
+    docs: HashMap<i64, Doc>,
+    /// Sum of all document lengths, for the average length.
+    total_len: usize,
+}
+
+#[derive(Debug)]
+struct Doc {
+    tokens: Vec<String>,
+    len: usize,
+}
+
+/// An advice matching a search, with its relevance.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Hit {
+    pub id: i64,
+    pub score: f64,
+}
+
+/// Matched part of an advice text, in characters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Highlight {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl SearchIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Indexes `advice`, replacing what was indexed for it before.
+    pub fn insert(&self, advice: &Advice) {
+        let mut index = self.inner.write().unwrap_or_else(PoisonError::into_inner);
+        index.remove(advice.id);
+
+        let mut counts = HashMap::<String, u32>::new();
+        let mut len = 0;
+        for (token, _) in tokenize(&advice.advice) {
+            *counts.entry(token).or_default() += 1;
+            len += 1;
+        }
+
+        for (token, count) in &counts {
+            index
+                .postings
+                .entry(token.clone())
+                .or_default()
+                .insert(advice.id, *count);
+        }
+        index.total_len += len;
+        index.docs.insert(
+            advice.id,
+            Doc {
+                tokens: counts.into_keys().collect(),
+                len,
+            },
+        );
+    }
+
+    pub fn remove(&self, id: i64) {
+        let mut index = self.inner.write().unwrap_or_else(PoisonError::into_inner);
+        index.remove(id);
+    }
+
+    /// Returns up to `limit` advices containing any token of `query`, most
+    /// relevant first.
+    pub fn search(&self, query: &str, limit: usize) -> Vec<Hit> {
+        let index = self.inner.read().unwrap_or_else(PoisonError::into_inner);
+        if index.docs.is_empty() {
+            return Vec::new();
+        }
+
+        let n = index.docs.len() as f64;
+        let avg_len = index.total_len as f64 / n;
+
+        let mut terms = tokenize(query).map(|(token, _)| token).collect::<Vec<_>>();
+        terms.sort();
+        terms.dedup();
+
+        let mut scores = HashMap::<i64, f64>::new();
+        for term in &terms {
+            let postings = match index.postings.get(term) {
+                Some(postings) => postings,
+                None => continue,
+            };
+
+            let df = postings.len() as f64;
+            let idf = ((n - df + 0.5) / (df + 0.5) + 1.0).ln();
+            for (id, &tf) in postings {
+                let tf = f64::from(tf);
+                let len = index.docs[id].len as f64;
+                let score = idf * tf * (K1 + 1.0) / (tf + K1 * (1.0 - B + B * len / avg_len));
+                *scores.entry(*id).or_default() += score;
+            }
+        }
+
+        let mut hits = scores
+            .into_iter()
+            .map(|(id, score)| Hit { id, score })
+            .collect::<Vec<_>>();
+        // Break ties by id so that results are stable.
+        hits.sort_by(|a, b| b.score.total_cmp(&a.score).then(a.id.cmp(&b.id)));
+        hits.truncate(limit);
+
+        hits
+    }
+}
+
+impl Index {
+    fn remove(&mut self, id: i64) {
+        let doc = match self.docs.remove(&id) {
+            Some(doc) => doc,
+            None => return,
+        };
+
+        self.total_len -= doc.len;
+        for token in doc.tokens {
+            if let Some(postings) = self.postings.get_mut(&token) {
+                postings.remove(&id);
+                if postings.is_empty() {
+                    self.postings.remove(&token);
+                }
+            }
+        }
+    }
+}
+
+/// Splits `text` into lowercased tokens with their byte ranges.
+pub fn tokenize(text: &str) -> impl Iterator<Item = (String, Range<usize>)> + '_ {
+    let mut chars = text.char_indices().peekable();
+
+    std::iter::from_fn(move || {
+        // Skip to the start of the next token.
+        while let Some(&(_, c)) = chars.peek() {
+            if c.is_alphanumeric() {
+                break;
+            }
+            chars.next();
+        }
+
+        let (start, _) = *chars.peek()?;
+        let mut end = start;
+        while let Some(&(i, c)) = chars.peek() {
+            if !c.is_alphanumeric() {
+                break;
+            }
+            end = i + c.len_utf8();
+            chars.next();
+        }
+
+        Some((text[start..end].to_lowercase(), start..end))
+    })
+}
+
+/// Spans of `text` matching any token of `query`, in character offsets.
+pub fn highlights(text: &str, query: &str) -> Vec<Highlight> {
+    let terms = tokenize(query).map(|(token, _)| token).collect::<Vec<_>>();
+
+    tokenize(text)
+        .filter(|(token, _)| terms.contains(token))
+        .map(|(_, range)| Highlight {
+            start: text[..range.start].chars().count(),
+            end: text[..range.end].chars().count(),
+        })
+        .collect()
+}
@@ -0,0 +1,164 @@
+use super::{AdviceStore, ListQuery, Page, Tag};
+use crate::{dedup::DuplicateIndex, search::SearchIndex, Advice, Error, Result};
+use async_trait::async_trait;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Wraps another store and keeps the search and duplicate indexes in sync
+/// with every change to advice text.
+///
+/// Inserts and updates fail with `Error::Conflict` when the new text is an
+/// exact or near duplicate of another stored advice.
+pub struct IndexedStore {
+    inner: Arc<dyn AdviceStore>,
+    search: Arc<SearchIndex>,
+    duplicates: Arc<DuplicateIndex>,
+    /// Serialises writes, so the index sees changes in the order the inner
+    /// store applied them.
+    writes: Mutex<()>,
+}
+
+impl IndexedStore {
+    /// Indexes everything already in `inner`.
+    pub async fn new(
+        inner: Arc<dyn AdviceStore>,
+        search: Arc<SearchIndex>,
+        duplicates: Arc<DuplicateIndex>,
+    ) -> Result<Self> {
+        let mut query = ListQuery {
+            limit: ListQuery::MAX_LIMIT,
+            ..ListQuery::default()
+        };
+        loop {
+            let page = inner.list(&query).await?;
+            for advice in &page.advices {
+                search.insert(advice);
+                duplicates.insert(advice);
+            }
+            match page.next_cursor {
+                Some(cursor) => query.after = Some(cursor),
+                None => break,
+            }
+        }
+
+        Ok(Self {
+            inner,
+            search,
+            duplicates,
+            writes: Mutex::new(()),
+        })
+    }
+
+    /// Fails if `text` duplicates a stored advice other than `exclude`.
+    fn check_duplicate(&self, exclude: Option<i64>, text: &str) -> Result<()> {
+        match self.duplicates.find(exclude, text) {
+            Some(found) if found.exact => Err(Error::Conflict(format!(
+                "advice {} already has the same text",
+                found.id
+            ))),
+            Some(found) => Err(Error::Conflict(format!(
+                "advice {} has a similar text ({:.0}% similar)",
+                found.id,
+                found.similarity * 100.0
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    fn index(&self, advice: &Advice) {
+        self.search.insert(advice);
+        self.duplicates.insert(advice);
+    }
+}
+
+#[async_trait]
+impl AdviceStore for IndexedStore {
+    async fn list(&self, query: &ListQuery) -> Result<Page> {
+        self.inner.list(query).await
+    }
+
+    async fn get(&self, id: i64) -> Result<Option<Advice>> {
+        self.inner.get(id).await
+    }
+
+    async fn count(&self, tag: Option<&str>) -> Result<usize> {
+        self.inner.count(tag).await
+    }
+
+    async fn nth(&self, tag: Option<&str>, index: usize) -> Result<Option<Advice>> {
+        self.inner.nth(tag, index).await
+    }
+
+    async fn insert(&self, advice: Advice) -> Result<()> {
+        let _writes = self.writes.lock().await;
+        self.check_duplicate(Some(advice.id), &advice.advice)?;
+        self.inner.insert(advice.clone()).await?;
+        self.index(&advice);
+        Ok(())
+    }
+
+    async fn insert_local(&self, advice: Advice) -> Result<Advice> {
+        let _writes = self.writes.lock().await;
+        // The id is not assigned yet, so nothing is exempt from the check.
+        self.check_duplicate(None, &advice.advice)?;
+        let advice = self.inner.insert_local(advice).await?;
+        self.index(&advice);
+        Ok(advice)
+    }
+
+    async fn delete(&self, id: i64) -> Result<bool> {
+        let _writes = self.writes.lock().await;
+        let deleted = self.inner.delete(id).await?;
+        self.search.remove(id);
+        self.duplicates.remove(id);
+        Ok(deleted)
+    }
+
+    async fn update(&self, advice: Advice, expected_version: Option<u64>) -> Result<Advice> {
+        let _writes = self.writes.lock().await;
+        self.check_duplicate(Some(advice.id), &advice.advice)?;
+        let advice = self.inner.update(advice, expected_version).await?;
+        self.index(&advice);
+        Ok(advice)
+    }
+
+    // Tags don't affect the text, so the indexes need no update for these.
+
+    async fn tags(&self) -> Result<Vec<Tag>> {
+        self.inner.tags().await
+    }
+
+    async fn create_tag(&self, name: &str) -> Result<Tag> {
+        self.inner.create_tag(name).await
+    }
+
+    async fn delete_tag(&self, name: &str) -> Result<bool> {
+        self.inner.delete_tag(name).await
+    }
+
+    async fn attach_tags(
+        &self,
+        id: i64,
+        tags: &[String],
+        expected_version: Option<u64>,
+    ) -> Result<Advice> {
+        self.inner.attach_tags(id, tags, expected_version).await
+    }
+
+    async fn detach_tag(
+        &self,
+        id: i64,
+        tag: &str,
+        expected_version: Option<u64>,
+    ) -> Result<Advice> {
+        self.inner.detach_tag(id, tag, expected_version).await
+    }
+
+    async fn daily_pick(&self, day: &str) -> Result<Option<i64>> {
+        self.inner.daily_pick(day).await
+    }
+
+    async fn record_daily_pick(&self, day: &str, id: i64, stale: Option<i64>) -> Result<i64> {
+        self.inner.record_daily_pick(day, id, stale).await
+    }
+}
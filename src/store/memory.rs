@@ -0,0 +1,267 @@
+use super::{check_version, text_key, AdviceStore, ListQuery, Page, Tag, LOCAL_ID_START};
+use crate::{Advice, Error, Result};
+use async_trait::async_trait;
+use chrono::Utc;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::RwLock,
+};
+
+/// Keeps advices in a process-local map; everything is lost on restart.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    inner: RwLock<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    advices: HashMap<i64, Advice>,
+    /// Ids of the advices carrying each tag. Tags without advices stay here
+    /// until deleted.
+    tags: BTreeMap<String, BTreeSet<i64>>,
+    /// Advice picked for each day.
+    daily_picks: HashMap<String, i64>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Inner {
+    /// Stores `advice`, keeping the tag index in sync with the advice it
+    /// replaces, if any.
+    fn put(&mut self, advice: Advice) {
+        self.remove(advice.id);
+        for tag in &advice.tags {
+            self.tags.entry(tag.clone()).or_default().insert(advice.id);
+        }
+        self.advices.insert(advice.id, advice);
+    }
+
+    fn remove(&mut self, id: i64) -> Option<Advice> {
+        let advice = self.advices.remove(&id)?;
+        for tag in &advice.tags {
+            if let Some(ids) = self.tags.get_mut(tag) {
+                ids.remove(&id);
+            }
+        }
+
+        Some(advice)
+    }
+
+    /// Applies `edit` to advice `id` and stores the result as a new version.
+    fn edit<F>(&mut self, id: i64, expected_version: Option<u64>, edit: F) -> Result<Advice>
+    where
+        F: FnOnce(&mut Advice),
+    {
+        let mut advice = self.advices.get(&id).ok_or(Error::NotFound)?.clone();
+        check_version(&advice, expected_version)?;
+
+        edit(&mut advice);
+        advice.updated_at = Utc::now();
+        advice.version += 1;
+        self.put(advice.clone());
+
+        Ok(advice)
+    }
+}
+
+#[async_trait]
+impl AdviceStore for MemoryStore {
+    async fn list(&self, query: &ListQuery) -> Result<Page> {
+        let inner = self.inner.read()?;
+
+        // Look up tagged advices through the index instead of scanning them all.
+        let mut matched = match &query.tag {
+            Some(tag) => inner
+                .tags
+                .get(tag)
+                .into_iter()
+                .flatten()
+                .filter_map(|id| inner.advices.get(id))
+                .filter(|advice| query.matches(advice))
+                .collect::<Vec<_>>(),
+            None => inner
+                .advices
+                .values()
+                .filter(|advice| query.matches(advice))
+                .collect(),
+        };
+        matched.sort_by(|a, b| query.sort.compare(a, b));
+        let matched = matched.into_iter().take(query.limit + 1).cloned().collect();
+
+        Ok(Page::from_sorted(matched, query.limit))
+    }
+
+    async fn get(&self, id: i64) -> Result<Option<Advice>> {
+        let inner = self.inner.read()?;
+        Ok(inner.advices.get(&id).cloned())
+    }
+
+    async fn count(&self, tag: Option<&str>) -> Result<usize> {
+        let inner = self.inner.read()?;
+        let count = match tag {
+            Some(tag) => inner.tags.get(tag).map_or(0, BTreeSet::len),
+            None => inner.advices.len(),
+        };
+
+        Ok(count)
+    }
+
+    async fn nth(&self, tag: Option<&str>, index: usize) -> Result<Option<Advice>> {
+        let inner = self.inner.read()?;
+        let id = match tag {
+            Some(tag) => inner
+                .tags
+                .get(tag)
+                .and_then(|ids| ids.iter().nth(index))
+                .copied(),
+            None => {
+                let mut ids = inner.advices.keys().copied().collect::<Vec<_>>();
+                if index < ids.len() {
+                    Some(*ids.select_nth_unstable(index).1)
+                } else {
+                    None
+                }
+            }
+        };
+
+        Ok(id.and_then(|id| inner.advices.get(&id)).cloned())
+    }
+
+    async fn insert(&self, mut advice: Advice) -> Result<()> {
+        advice.version = 1;
+        let mut inner = self.inner.write()?;
+        inner.put(advice);
+        Ok(())
+    }
+
+    async fn insert_local(&self, mut advice: Advice) -> Result<Advice> {
+        let mut inner = self.inner.write()?;
+
+        let key = text_key(&advice.advice);
+        if let Some(duplicate) = inner
+            .advices
+            .values()
+            .find(|existing| text_key(&existing.advice) == key)
+        {
+            return Err(Error::Conflict(format!(
+                "advice {} already has the same text",
+                duplicate.id
+            )));
+        }
+
+        advice.version = 1;
+        advice.id = inner
+            .advices
+            .keys()
+            .filter(|&&id| id >= LOCAL_ID_START)
+            .max()
+            .map_or(LOCAL_ID_START, |id| id + 1);
+        inner.put(advice.clone());
+
+        Ok(advice)
+    }
+
+    async fn delete(&self, id: i64) -> Result<bool> {
+        let mut inner = self.inner.write()?;
+        Ok(inner.remove(id).is_some())
+    }
+
+    async fn update(&self, advice: Advice, expected_version: Option<u64>) -> Result<Advice> {
+        let mut inner = self.inner.write()?;
+        inner.edit(advice.id, expected_version, |existing| {
+            existing.advice = advice.advice;
+            existing.tags = advice.tags;
+            existing.author = advice.author;
+        })
+    }
+
+    async fn tags(&self) -> Result<Vec<Tag>> {
+        let inner = self.inner.read()?;
+        let tags = inner
+            .tags
+            .iter()
+            .map(|(name, ids)| Tag {
+                name: name.clone(),
+                count: ids.len(),
+            })
+            .collect();
+
+        Ok(tags)
+    }
+
+    async fn create_tag(&self, name: &str) -> Result<Tag> {
+        let mut inner = self.inner.write()?;
+        if inner.tags.contains_key(name) {
+            return Err(Error::Conflict(format!("tag {:?} already exists", name)));
+        }
+        inner.tags.insert(name.to_owned(), BTreeSet::new());
+
+        Ok(Tag {
+            name: name.to_owned(),
+            count: 0,
+        })
+    }
+
+    async fn delete_tag(&self, name: &str) -> Result<bool> {
+        let mut inner = self.inner.write()?;
+        let ids = match inner.tags.remove(name) {
+            Some(ids) => ids,
+            None => return Ok(false),
+        };
+
+        for id in ids {
+            inner.edit(id, None, |advice| advice.tags.retain(|tag| tag != name))?;
+        }
+
+        Ok(true)
+    }
+
+    async fn attach_tags(
+        &self,
+        id: i64,
+        tags: &[String],
+        expected_version: Option<u64>,
+    ) -> Result<Advice> {
+        let mut inner = self.inner.write()?;
+        inner.edit(id, expected_version, |advice| {
+            advice.tags.extend(tags.iter().cloned());
+            advice.tags.sort();
+            advice.tags.dedup();
+        })
+    }
+
+    async fn detach_tag(
+        &self,
+        id: i64,
+        tag: &str,
+        expected_version: Option<u64>,
+    ) -> Result<Advice> {
+        let mut inner = self.inner.write()?;
+        let advice = inner.advices.get(&id).ok_or(Error::NotFound)?;
+        if !advice.tags.iter().any(|t| t == tag) {
+            return Err(Error::TagNotFound(tag.to_owned()));
+        }
+
+        inner.edit(id, expected_version, |advice| {
+            advice.tags.retain(|t| t != tag)
+        })
+    }
+
+    async fn daily_pick(&self, day: &str) -> Result<Option<i64>> {
+        Ok(self.inner.read()?.daily_picks.get(day).copied())
+    }
+
+    async fn record_daily_pick(&self, day: &str, id: i64, stale: Option<i64>) -> Result<i64> {
+        let mut inner = self.inner.write()?;
+        let pick = inner.daily_picks.entry(day.to_owned()).or_insert(id);
+        if Some(*pick) == stale {
+            *pick = id;
+        }
+
+        Ok(*pick)
+    }
+}
@@ -0,0 +1,128 @@
+use crate::{Advice, Error, Result};
+use async_trait::async_trait;
+use serde::Serialize;
+
+mod indexed;
+mod memory;
+mod query;
+mod sqlite;
+
+pub use indexed::IndexedStore;
+pub use memory::MemoryStore;
+pub use query::{Cursor, ListQuery, Page, Sort};
+pub use sqlite::SqliteStore;
+
+/// Ids handed out by `AdviceStore::insert_local` start here, far above the
+/// ids used by upstream providers, so the two never collide.
+pub const LOCAL_ID_START: i64 = 1_000_000_000;
+
+/// A tag and the number of advices carrying it.
+#[derive(Debug, Clone, Serialize)]
+pub struct Tag {
+    pub name: String,
+    pub count: usize,
+}
+
+/// Storage backend for advices.
+///
+/// Handlers only ever talk to an `Arc<dyn AdviceStore>`, so any backend that
+/// implements this trait can be plugged in from `main`.
+#[async_trait]
+pub trait AdviceStore: Send + Sync {
+    /// Returns one page of advices matching `query`, in its sort order.
+    async fn list(&self, query: &ListQuery) -> Result<Page>;
+
+    async fn get(&self, id: i64) -> Result<Option<Advice>>;
+
+    /// Number of stored advices, only counting those carrying `tag` if given.
+    async fn count(&self, tag: Option<&str>) -> Result<usize>;
+
+    /// Returns the advice at position `index` in id order among those counted
+    /// by `count`, or `None` past the end.
+    async fn nth(&self, tag: Option<&str>, index: usize) -> Result<Option<Advice>>;
+
+    /// Inserts an advice at version 1, replacing any existing one with the
+    /// same id.
+    async fn insert(&self, advice: Advice) -> Result<()>;
+
+    /// Stores a user-submitted advice under a fresh id from the local id
+    /// range and returns it with that id.
+    ///
+    /// Fails with `Error::Conflict` if an advice with the same text, ignoring
+    /// case and surrounding whitespace, is already stored.
+    async fn insert_local(&self, advice: Advice) -> Result<Advice>;
+
+    /// Returns `false` if there was no advice with the given id.
+    async fn delete(&self, id: i64) -> Result<bool>;
+
+    /// Replaces the editable fields (text, tags and author) of the stored
+    /// advice with the same id, bumps its version and `updated_at`, and
+    /// returns the updated advice.
+    ///
+    /// If `expected_version` is given and the stored advice is at another
+    /// version, nothing changes and this fails with
+    /// `Error::PreconditionFailed`.
+    async fn update(&self, advice: Advice, expected_version: Option<u64>) -> Result<Advice>;
+
+    /// Returns every known tag, sorted by name. Tags are known once created
+    /// explicitly or attached to an advice, until they are deleted.
+    async fn tags(&self) -> Result<Vec<Tag>>;
+
+    /// Fails with `Error::Conflict` if the tag already exists.
+    async fn create_tag(&self, name: &str) -> Result<Tag>;
+
+    /// Deletes a tag and detaches it from every advice, bumping their
+    /// versions. Returns `false` if there was no such tag.
+    async fn delete_tag(&self, name: &str) -> Result<bool>;
+
+    /// Adds `tags` to advice `id`, creating tags that don't exist yet.
+    ///
+    /// Versions are checked and bumped as in `update`.
+    async fn attach_tags(
+        &self,
+        id: i64,
+        tags: &[String],
+        expected_version: Option<u64>,
+    ) -> Result<Advice>;
+
+    /// Removes `tag` from advice `id`, failing with `Error::TagNotFound` if
+    /// the advice doesn't carry it.
+    ///
+    /// Versions are checked and bumped as in `update`.
+    async fn detach_tag(&self, id: i64, tag: &str, expected_version: Option<u64>)
+        -> Result<Advice>;
+
+    /// Id of the advice picked for `day`, a key naming a date in a time
+    /// zone, if one was recorded.
+    async fn daily_pick(&self, day: &str) -> Result<Option<i64>>;
+
+    /// Records advice `id` as the pick for `day` and returns the recorded
+    /// pick. A pick recorded meanwhile, by another replica say, is kept
+    /// unless it is `stale`.
+    async fn record_daily_pick(&self, day: &str, id: i64, stale: Option<i64>) -> Result<i64>;
+}
+
+/// Folds the case of `text` for case-insensitive matching. SQLite's `lower`
+/// only folds ASCII letters, so `SqliteStore` registers this as `fold_case`
+/// for both stores to match the same advices.
+fn fold_case(text: &str) -> String {
+    text.to_lowercase()
+}
+
+/// What two advice texts must have in common to be the same advice,
+/// registered in SQLite as `text_key`.
+fn text_key(text: &str) -> String {
+    fold_case(text.trim())
+}
+
+/// Fails with `Error::PreconditionFailed` unless `advice` is at the expected
+/// version.
+fn check_version(advice: &Advice, expected_version: Option<u64>) -> Result<()> {
+    match expected_version {
+        Some(expected) if expected != advice.version => Err(Error::PreconditionFailed(format!(
+            "advice {} is at version {}, not {}",
+            advice.id, advice.version, expected
+        ))),
+        _ => Ok(()),
+    }
+}
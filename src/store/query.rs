@@ -0,0 +1,221 @@
+use super::fold_case;
+use crate::{Advice, Error};
+use serde::{Serialize, Serializer};
+use std::{cmp::Ordering, fmt, str::FromStr};
+
+/// Parameters of a single page of `AdviceStore::list`.
+#[derive(Debug, Clone)]
+pub struct ListQuery {
+    pub limit: usize,
+    pub sort: Sort,
+    /// Only return advices that sort after this cursor.
+    pub after: Option<Cursor>,
+    /// Case-insensitive substring the advice text must contain.
+    pub q: Option<String>,
+    /// Tag the advice must carry.
+    pub tag: Option<String>,
+}
+
+impl ListQuery {
+    pub const DEFAULT_LIMIT: usize = 50;
+    pub const MAX_LIMIT: usize = 500;
+
+    /// Whether `advice` passes the filters and comes after the cursor.
+    pub fn matches(&self, advice: &Advice) -> bool {
+        if let Some(tag) = &self.tag {
+            if !advice.tags.contains(tag) {
+                return false;
+            }
+        }
+
+        if let Some(q) = &self.q {
+            if !fold_case(&advice.advice).contains(&fold_case(q)) {
+                return false;
+            }
+        }
+
+        match &self.after {
+            Some(cursor) => self.sort.compare_cursor(advice, cursor) == Ordering::Greater,
+            None => true,
+        }
+    }
+}
+
+impl Default for ListQuery {
+    fn default() -> Self {
+        Self {
+            limit: Self::DEFAULT_LIMIT,
+            sort: Sort::default(),
+            after: None,
+            q: None,
+            tag: None,
+        }
+    }
+}
+
+/// Order of listed advices, written as `id`, `-id`, `created_at` or
+/// `-created_at` in query strings.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Sort {
+    #[default]
+    IdAsc,
+    IdDesc,
+    CreatedAsc,
+    CreatedDesc,
+}
+
+impl Sort {
+    /// Compares two advices in this order. Ties on creation time are broken
+    /// by id so that the order is total and cursors are unambiguous.
+    pub fn compare(self, a: &Advice, b: &Advice) -> Ordering {
+        self.compare_cursor(a, &Cursor::of(b))
+    }
+
+    fn compare_cursor(self, advice: &Advice, cursor: &Cursor) -> Ordering {
+        let created_at = advice.created_at.timestamp_millis();
+        match self {
+            Sort::IdAsc => advice.id.cmp(&cursor.id),
+            Sort::IdDesc => cursor.id.cmp(&advice.id),
+            Sort::CreatedAsc => (created_at, advice.id).cmp(&(cursor.created_at, cursor.id)),
+            Sort::CreatedDesc => (cursor.created_at, cursor.id).cmp(&(created_at, advice.id)),
+        }
+    }
+}
+
+impl FromStr for Sort {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "id" => Ok(Sort::IdAsc),
+            "-id" => Ok(Sort::IdDesc),
+            "created_at" => Ok(Sort::CreatedAsc),
+            "-created_at" => Ok(Sort::CreatedDesc),
+            _ => Err(Error::BadRequest(format!(
+                "unknown sort {:?}, expected one of id, -id, created_at, -created_at",
+                s
+            ))),
+        }
+    }
+}
+
+/// Position of the last advice of a page, handed to clients as
+/// `next_cursor` and sent back to fetch the following page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cursor {
+    pub id: i64,
+    /// Creation time in milliseconds since the Unix epoch.
+    pub created_at: i64,
+}
+
+impl Cursor {
+    pub fn of(advice: &Advice) -> Self {
+        Self {
+            id: advice.id,
+            created_at: advice.created_at.timestamp_millis(),
+        }
+    }
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}_{}", self.created_at, self.id)
+    }
+}
+
+impl Serialize for Cursor {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::BadRequest(format!("invalid cursor {:?}", s));
+        let (created_at, id) = s.split_once('_').ok_or_else(invalid)?;
+
+        Ok(Self {
+            id: id.parse().map_err(|_| invalid())?,
+            created_at: created_at.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// One page of listed advices.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Page {
+    pub advices: Vec<Advice>,
+    /// Set when there may be more advices after this page.
+    pub next_cursor: Option<Cursor>,
+}
+
+impl Page {
+    /// Builds a page from up to `limit + 1` sorted advices; the extra one
+    /// only tells whether another page follows.
+    pub fn from_sorted(mut advices: Vec<Advice>, limit: usize) -> Self {
+        let next_cursor = if advices.len() > limit {
+            advices.truncate(limit);
+            advices.last().map(Cursor::of)
+        } else {
+            None
+        };
+
+        Self {
+            advices,
+            next_cursor,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn cursor_round_trips() {
+        for cursor in [
+            Cursor {
+                id: 42,
+                created_at: 1_600_000_000_123,
+            },
+            Cursor {
+                id: 0,
+                created_at: 0,
+            },
+            Cursor {
+                id: -1,
+                created_at: -86_400_000,
+            },
+        ] {
+            assert_eq!(cursor.to_string().parse::<Cursor>().unwrap(), cursor);
+        }
+    }
+
+    #[test]
+    fn cursor_of_advice_keeps_milliseconds() {
+        let mut advice = Advice::new(7, "advice".to_owned(), Advice::SOURCE_USER);
+        advice.created_at = Utc.timestamp_millis_opt(1_600_000_000_123).unwrap();
+
+        let cursor = Cursor::of(&advice);
+        assert_eq!(cursor.to_string(), "1600000000123_7");
+        assert_eq!(
+            serde_json::to_string(&cursor).unwrap(),
+            "\"1600000000123_7\""
+        );
+        assert_eq!(cursor.to_string().parse::<Cursor>().unwrap(), cursor);
+    }
+
+    #[test]
+    fn cursor_rejects_malformed_strings() {
+        for s in ["", "12", "_", "a_1", "1_b", "1_2_3", "1.5_2", " 1_2"] {
+            assert!(
+                matches!(s.parse::<Cursor>(), Err(Error::BadRequest(_))),
+                "{:?}",
+                s
+            );
+        }
+    }
+}
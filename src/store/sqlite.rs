@@ -0,0 +1,875 @@
+use super::{
+    check_version, fold_case, text_key, AdviceStore, ListQuery, Page, Sort, Tag, LOCAL_ID_START,
+};
+use crate::{
+    auth::{ApiKey, KeyStore},
+    Advice, Error, Result,
+};
+use anyhow::Context;
+use async_trait::async_trait;
+use chrono::{DateTime, TimeZone, Utc};
+use rusqlite::{
+    functions::FunctionFlags, params, params_from_iter, types::Value, Connection,
+    OptionalExtension, Row,
+};
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+/// Schema migrations, applied in order. `PRAGMA user_version` records how
+/// many of them the database has already seen, so only append to this list.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE advices (
+        id INTEGER PRIMARY KEY,
+        advice TEXT NOT NULL
+    );",
+    // Creation time in milliseconds since the Unix epoch; advices stored
+    // before it was tracked count as created by the migration.
+    "ALTER TABLE advices ADD COLUMN created_at INTEGER NOT NULL DEFAULT 0;
+    UPDATE advices SET created_at = CAST(strftime('%s', 'now') AS INTEGER) * 1000;
+    CREATE INDEX advices_created_at ON advices (created_at, id);",
+    "ALTER TABLE advices ADD COLUMN version INTEGER NOT NULL DEFAULT 1;",
+    "ALTER TABLE advices ADD COLUMN source TEXT NOT NULL DEFAULT 'unknown';
+    ALTER TABLE advices ADD COLUMN author TEXT;
+    ALTER TABLE advices ADD COLUMN updated_at INTEGER NOT NULL DEFAULT 0;
+    UPDATE advices SET updated_at = created_at;
+    CREATE TABLE advice_tags (
+        advice_id INTEGER NOT NULL,
+        tag TEXT NOT NULL,
+        PRIMARY KEY (advice_id, tag)
+    );",
+    "CREATE TABLE tags (
+        name TEXT PRIMARY KEY,
+        created_at INTEGER NOT NULL
+    );
+    INSERT INTO tags (name, created_at)
+        SELECT DISTINCT tag, CAST(strftime('%s', 'now') AS INTEGER) * 1000 FROM advice_tags;
+    CREATE INDEX advice_tags_tag ON advice_tags (tag, advice_id);",
+    // API keys, stored by hash; scopes are comma-separated.
+    "CREATE TABLE api_keys (
+        id TEXT PRIMARY KEY,
+        name TEXT NOT NULL,
+        hash TEXT NOT NULL UNIQUE,
+        scopes TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        revoked_at INTEGER
+    );",
+    // Who submitted user advices: an API key id or a JWT subject.
+    "ALTER TABLE advices ADD COLUMN created_by TEXT;",
+    // Advice of the day, keyed by date and time zone, so that it doesn't
+    // change with the advices stored during the day.
+    "CREATE TABLE daily_picks (
+        day TEXT PRIMARY KEY,
+        advice_id INTEGER NOT NULL
+    );",
+];
+
+/// Tags are aggregated into one comma-separated column; `Advice::validate_tag`
+/// keeps commas out of them.
+const ADVICE_COLUMNS: &str =
+    "id, advice, source, author, created_by, created_at, updated_at, version,
+    (SELECT group_concat(tag) FROM
+        (SELECT tag FROM advice_tags WHERE advice_id = advices.id ORDER BY tag)) AS tags";
+
+/// Keeps advices in a SQLite database file so they survive restarts. API keys
+/// are kept in the same database.
+#[derive(Clone)]
+pub struct SqliteStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+    /// Opens (or creates) the database at `path` and brings its schema up to
+    /// date.
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let mut conn = Connection::open(path)
+            .with_context(|| format!("failed to open SQLite database {}", path.display()))?;
+        register_functions(&conn)?;
+        migrate(&mut conn)?;
+
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Runs `f` against the connection on the blocking thread pool.
+    async fn run<F, T>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&Connection) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.lock()?;
+            f(&conn)
+        })
+        .await?
+    }
+}
+
+/// Registers the Rust functions queries fold text with, so that they match
+/// the same advices as `MemoryStore`.
+fn register_functions(conn: &Connection) -> rusqlite::Result<()> {
+    let flags = FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC;
+    conn.create_scalar_function("fold_case", 1, flags, |ctx| {
+        Ok(fold_case(&ctx.get::<String>(0)?))
+    })?;
+    conn.create_scalar_function("text_key", 1, flags, |ctx| {
+        Ok(text_key(&ctx.get::<String>(0)?))
+    })
+}
+
+fn migrate(conn: &mut Connection) -> anyhow::Result<()> {
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)
+            .with_context(|| format!("failed to apply migration {}", i + 1))?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+        tx.commit()?;
+        tracing::info!("applied SQLite migration {}", i + 1);
+    }
+
+    Ok(())
+}
+
+fn advice_from_row(row: &Row) -> rusqlite::Result<Advice> {
+    let tags: Option<String> = row.get("tags")?;
+
+    Ok(Advice {
+        id: row.get("id")?,
+        advice: row.get("advice")?,
+        source: row.get("source")?,
+        tags: tags
+            .map(|tags| tags.split(',').map(ToOwned::to_owned).collect())
+            .unwrap_or_default(),
+        author: row.get("author")?,
+        created_by: row.get("created_by")?,
+        created_at: timestamp_from_row(row, "created_at")?,
+        updated_at: timestamp_from_row(row, "updated_at")?,
+        version: row.get("version")?,
+    })
+}
+
+/// Reads a timestamp stored as milliseconds since the Unix epoch.
+fn timestamp_from_row(row: &Row, column: &str) -> rusqlite::Result<DateTime<Utc>> {
+    let millis: i64 = row.get(column)?;
+
+    Utc.timestamp_millis_opt(millis).single().ok_or_else(|| {
+        rusqlite::Error::IntegralValueOutOfRange(
+            row.as_ref().column_index(column).unwrap_or_default(),
+            millis,
+        )
+    })
+}
+
+fn key_from_row(row: &Row) -> rusqlite::Result<ApiKey> {
+    let scopes: String = row.get("scopes")?;
+    let revoked_at: Option<i64> = row.get("revoked_at")?;
+
+    Ok(ApiKey {
+        id: row.get("id")?,
+        name: row.get("name")?,
+        hash: row.get("hash")?,
+        // Scopes this build doesn't know are dropped rather than granted.
+        scopes: scopes
+            .split(',')
+            .filter_map(|scope| scope.parse().ok())
+            .collect(),
+        created_at: timestamp_from_row(row, "created_at")?,
+        revoked_at: match revoked_at {
+            Some(_) => Some(timestamp_from_row(row, "revoked_at")?),
+            None => None,
+        },
+    })
+}
+
+/// Replaces the tags of advice `id` with `tags`, creating tags that don't
+/// exist yet.
+fn set_tags(conn: &Connection, id: i64, tags: &[String]) -> Result<()> {
+    conn.execute("DELETE FROM advice_tags WHERE advice_id = ?1", params![id])?;
+
+    let mut create =
+        conn.prepare("INSERT OR IGNORE INTO tags (name, created_at) VALUES (?1, ?2)")?;
+    let mut attach = conn.prepare("INSERT INTO advice_tags (advice_id, tag) VALUES (?1, ?2)")?;
+    let now = Utc::now().timestamp_millis();
+    for tag in tags {
+        create.execute(params![tag, now])?;
+        attach.execute(params![id, tag])?;
+    }
+
+    Ok(())
+}
+
+/// Applies `edit` to advice `id` and stores the result as a new version.
+/// Must run inside a transaction.
+fn edit_advice<F>(
+    conn: &Connection,
+    id: i64,
+    expected_version: Option<u64>,
+    edit: F,
+) -> Result<Advice>
+where
+    F: FnOnce(&mut Advice),
+{
+    let mut advice = get_advice(conn, id)?.ok_or(Error::NotFound)?;
+    check_version(&advice, expected_version)?;
+
+    edit(&mut advice);
+    conn.execute(
+        "UPDATE advices
+        SET advice = ?2, author = ?3, updated_at = ?4, version = version + 1
+        WHERE id = ?1",
+        params![
+            id,
+            advice.advice,
+            advice.author,
+            Utc::now().timestamp_millis()
+        ],
+    )?;
+    set_tags(conn, id, &advice.tags)?;
+
+    get_advice(conn, id)?.ok_or(Error::NotFound)
+}
+
+fn get_advice(conn: &Connection, id: i64) -> Result<Option<Advice>> {
+    let advice = conn
+        .query_row(
+            &format!("SELECT {} FROM advices WHERE id = ?1", ADVICE_COLUMNS),
+            params![id],
+            advice_from_row,
+        )
+        .optional()?;
+
+    Ok(advice)
+}
+
+/// Builds the `SELECT` for one page of `query`, fetching one extra row to
+/// tell whether another page follows.
+fn list_sql(query: &ListQuery) -> (String, Vec<Value>) {
+    let mut sql = format!("SELECT {} FROM advices WHERE 1 = 1", ADVICE_COLUMNS);
+    let mut params = Vec::new();
+
+    if let Some(q) = &query.q {
+        sql.push_str(" AND instr(fold_case(advice), ?) > 0");
+        params.push(Value::Text(fold_case(q)));
+    }
+
+    if let Some(tag) = &query.tag {
+        sql.push_str(" AND id IN (SELECT advice_id FROM advice_tags WHERE tag = ?)");
+        params.push(Value::Text(tag.clone()));
+    }
+
+    if let Some(cursor) = &query.after {
+        match query.sort {
+            Sort::IdAsc => sql.push_str(" AND id > ?"),
+            Sort::IdDesc => sql.push_str(" AND id < ?"),
+            Sort::CreatedAsc => sql.push_str(" AND (created_at, id) > (?, ?)"),
+            Sort::CreatedDesc => sql.push_str(" AND (created_at, id) < (?, ?)"),
+        }
+        if matches!(query.sort, Sort::CreatedAsc | Sort::CreatedDesc) {
+            params.push(Value::Integer(cursor.created_at));
+        }
+        params.push(Value::Integer(cursor.id));
+    }
+
+    sql.push_str(match query.sort {
+        Sort::IdAsc => " ORDER BY id ASC",
+        Sort::IdDesc => " ORDER BY id DESC",
+        Sort::CreatedAsc => " ORDER BY created_at ASC, id ASC",
+        Sort::CreatedDesc => " ORDER BY created_at DESC, id DESC",
+    });
+    sql.push_str(" LIMIT ?");
+    params.push(Value::Integer(query.limit as i64 + 1));
+
+    (sql, params)
+}
+
+#[async_trait]
+impl AdviceStore for SqliteStore {
+    async fn list(&self, query: &ListQuery) -> Result<Page> {
+        let limit = query.limit;
+        let (sql, params) = list_sql(query);
+
+        self.run(move |conn| {
+            let mut stmt = conn.prepare(&sql)?;
+            let advices = stmt
+                .query_map(params_from_iter(params), advice_from_row)?
+                .collect::<rusqlite::Result<_>>()?;
+
+            Ok(Page::from_sorted(advices, limit))
+        })
+        .await
+    }
+
+    async fn get(&self, id: i64) -> Result<Option<Advice>> {
+        self.run(move |conn| get_advice(conn, id)).await
+    }
+
+    async fn count(&self, tag: Option<&str>) -> Result<usize> {
+        let tag = tag.map(ToOwned::to_owned);
+        self.run(move |conn| {
+            let count = match tag {
+                Some(tag) => conn.query_row(
+                    "SELECT COUNT(*) FROM advice_tags WHERE tag = ?1",
+                    params![tag],
+                    |row| row.get(0),
+                )?,
+                None => conn.query_row("SELECT COUNT(*) FROM advices", [], |row| row.get(0))?,
+            };
+
+            Ok(count)
+        })
+        .await
+    }
+
+    async fn nth(&self, tag: Option<&str>, index: usize) -> Result<Option<Advice>> {
+        let tag = tag.map(ToOwned::to_owned);
+        self.run(move |conn| {
+            let (filter, params) = match tag {
+                Some(tag) => (
+                    "WHERE id IN (SELECT advice_id FROM advice_tags WHERE tag = ?)",
+                    vec![Value::Text(tag), Value::Integer(index as i64)],
+                ),
+                None => ("", vec![Value::Integer(index as i64)]),
+            };
+            let sql = format!(
+                "SELECT {} FROM advices {} ORDER BY id LIMIT 1 OFFSET ?",
+                ADVICE_COLUMNS, filter
+            );
+            let advice = conn
+                .query_row(&sql, params_from_iter(params), advice_from_row)
+                .optional()?;
+
+            Ok(advice)
+        })
+        .await
+    }
+
+    async fn insert(&self, advice: Advice) -> Result<()> {
+        self.run(move |conn| {
+            let tx = conn.unchecked_transaction()?;
+            tx.execute(
+                "INSERT OR REPLACE INTO advices
+                (id, advice, source, author, created_by, created_at, updated_at, version)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, 1)",
+                params![
+                    advice.id,
+                    advice.advice,
+                    advice.source,
+                    advice.author,
+                    advice.created_by,
+                    advice.created_at.timestamp_millis(),
+                    advice.updated_at.timestamp_millis(),
+                ],
+            )?;
+            set_tags(&tx, advice.id, &advice.tags)?;
+            tx.commit()?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn insert_local(&self, advice: Advice) -> Result<Advice> {
+        self.run(move |conn| {
+            let duplicate = conn
+                .query_row(
+                    "SELECT id FROM advices WHERE text_key(advice) = ?1 LIMIT 1",
+                    params![text_key(&advice.advice)],
+                    |row| row.get::<_, i64>(0),
+                )
+                .optional()?;
+            if let Some(id) = duplicate {
+                return Err(Error::Conflict(format!(
+                    "advice {} already has the same text",
+                    id
+                )));
+            }
+
+            let tx = conn.unchecked_transaction()?;
+            tx.execute(
+                "INSERT INTO advices
+                (id, advice, source, author, created_by, created_at, updated_at, version)
+                SELECT MAX(?1, COALESCE(MAX(id) + 1, ?1)), ?2, ?3, ?4, ?5, ?6, ?7, 1
+                FROM advices WHERE id >= ?1",
+                params![
+                    LOCAL_ID_START,
+                    advice.advice,
+                    advice.source,
+                    advice.author,
+                    advice.created_by,
+                    advice.created_at.timestamp_millis(),
+                    advice.updated_at.timestamp_millis(),
+                ],
+            )?;
+            let id = tx.last_insert_rowid();
+            set_tags(&tx, id, &advice.tags)?;
+            tx.commit()?;
+
+            Ok(Advice {
+                id,
+                version: 1,
+                ..advice
+            })
+        })
+        .await
+    }
+
+    async fn delete(&self, id: i64) -> Result<bool> {
+        self.run(move |conn| {
+            let tx = conn.unchecked_transaction()?;
+            let deleted = tx.execute("DELETE FROM advices WHERE id = ?1", params![id])?;
+            tx.execute("DELETE FROM advice_tags WHERE advice_id = ?1", params![id])?;
+            tx.commit()?;
+
+            Ok(deleted > 0)
+        })
+        .await
+    }
+
+    async fn update(&self, advice: Advice, expected_version: Option<u64>) -> Result<Advice> {
+        self.run(move |conn| {
+            let tx = conn.unchecked_transaction()?;
+            let updated = edit_advice(&tx, advice.id, expected_version, |existing| {
+                existing.advice = advice.advice;
+                existing.tags = advice.tags;
+                existing.author = advice.author;
+            })?;
+            tx.commit()?;
+
+            Ok(updated)
+        })
+        .await
+    }
+
+    async fn tags(&self) -> Result<Vec<Tag>> {
+        self.run(|conn| {
+            let mut stmt = conn.prepare(
+                "SELECT tags.name, COUNT(advice_tags.advice_id) FROM tags
+                LEFT JOIN advice_tags ON advice_tags.tag = tags.name
+                GROUP BY tags.name ORDER BY tags.name",
+            )?;
+            let tags = stmt
+                .query_map([], |row| {
+                    Ok(Tag {
+                        name: row.get(0)?,
+                        count: row.get(1)?,
+                    })
+                })?
+                .collect::<rusqlite::Result<_>>()?;
+
+            Ok(tags)
+        })
+        .await
+    }
+
+    async fn create_tag(&self, name: &str) -> Result<Tag> {
+        let name = name.to_owned();
+        self.run(move |conn| {
+            let created = conn.execute(
+                "INSERT OR IGNORE INTO tags (name, created_at) VALUES (?1, ?2)",
+                params![name, Utc::now().timestamp_millis()],
+            )?;
+            if created == 0 {
+                return Err(Error::Conflict(format!("tag {:?} already exists", name)));
+            }
+
+            Ok(Tag { name, count: 0 })
+        })
+        .await
+    }
+
+    async fn delete_tag(&self, name: &str) -> Result<bool> {
+        let name = name.to_owned();
+        self.run(move |conn| {
+            let tx = conn.unchecked_transaction()?;
+            let ids = tx
+                .prepare("SELECT advice_id FROM advice_tags WHERE tag = ?1")?
+                .query_map(params![name], |row| row.get::<_, i64>(0))?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            for id in ids {
+                edit_advice(&tx, id, None, |advice| {
+                    advice.tags.retain(|tag| *tag != name)
+                })?;
+            }
+            let deleted = tx.execute("DELETE FROM tags WHERE name = ?1", params![name])?;
+            tx.commit()?;
+
+            Ok(deleted > 0)
+        })
+        .await
+    }
+
+    async fn attach_tags(
+        &self,
+        id: i64,
+        tags: &[String],
+        expected_version: Option<u64>,
+    ) -> Result<Advice> {
+        let tags = tags.to_vec();
+        self.run(move |conn| {
+            let tx = conn.unchecked_transaction()?;
+            let advice = edit_advice(&tx, id, expected_version, |advice| {
+                advice.tags.extend(tags);
+                advice.tags.sort();
+                advice.tags.dedup();
+            })?;
+            tx.commit()?;
+
+            Ok(advice)
+        })
+        .await
+    }
+
+    async fn detach_tag(
+        &self,
+        id: i64,
+        tag: &str,
+        expected_version: Option<u64>,
+    ) -> Result<Advice> {
+        let tag = tag.to_owned();
+        self.run(move |conn| {
+            let tx = conn.unchecked_transaction()?;
+            let advice = get_advice(&tx, id)?.ok_or(Error::NotFound)?;
+            if !advice.tags.contains(&tag) {
+                return Err(Error::TagNotFound(tag));
+            }
+
+            let advice = edit_advice(&tx, id, expected_version, |advice| {
+                advice.tags.retain(|t| *t != tag)
+            })?;
+            tx.commit()?;
+
+            Ok(advice)
+        })
+        .await
+    }
+
+    async fn daily_pick(&self, day: &str) -> Result<Option<i64>> {
+        let day = day.to_owned();
+        self.run(move |conn| {
+            let id = conn
+                .query_row(
+                    "SELECT advice_id FROM daily_picks WHERE day = ?1",
+                    params![day],
+                    |row| row.get(0),
+                )
+                .optional()?;
+
+            Ok(id)
+        })
+        .await
+    }
+
+    async fn record_daily_pick(&self, day: &str, id: i64, stale: Option<i64>) -> Result<i64> {
+        let day = day.to_owned();
+        self.run(move |conn| {
+            let tx = conn.unchecked_transaction()?;
+            tx.execute(
+                "INSERT INTO daily_picks (day, advice_id) VALUES (?1, ?2)
+                ON CONFLICT (day) DO UPDATE SET advice_id = excluded.advice_id
+                WHERE advice_id IS ?3",
+                params![day, id, stale],
+            )?;
+            let pick = tx.query_row(
+                "SELECT advice_id FROM daily_picks WHERE day = ?1",
+                params![day],
+                |row| row.get(0),
+            )?;
+            tx.commit()?;
+
+            Ok(pick)
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl KeyStore for SqliteStore {
+    async fn insert_key(&self, key: ApiKey) -> Result<()> {
+        self.run(move |conn| {
+            let scopes = key
+                .scopes
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(",");
+            conn.execute(
+                "INSERT INTO api_keys (id, name, hash, scopes, created_at, revoked_at)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    key.id,
+                    key.name,
+                    key.hash,
+                    scopes,
+                    key.created_at.timestamp_millis(),
+                    key.revoked_at.map(|at| at.timestamp_millis()),
+                ],
+            )?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn find_key(&self, hash: &str) -> Result<Option<ApiKey>> {
+        let hash = hash.to_owned();
+        self.run(move |conn| {
+            let key = conn
+                .query_row(
+                    "SELECT * FROM api_keys WHERE hash = ?1 AND revoked_at IS NULL",
+                    params![hash],
+                    key_from_row,
+                )
+                .optional()?;
+
+            Ok(key)
+        })
+        .await
+    }
+
+    async fn keys(&self) -> Result<Vec<ApiKey>> {
+        self.run(|conn| {
+            let keys = conn
+                .prepare("SELECT * FROM api_keys ORDER BY created_at, id")?
+                .query_map([], key_from_row)?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+
+            Ok(keys)
+        })
+        .await
+    }
+
+    async fn revoke_key(&self, id: &str) -> Result<bool> {
+        let id = id.to_owned();
+        self.run(move |conn| {
+            let revoked = conn.execute(
+                "UPDATE api_keys SET revoked_at = ?2 WHERE id = ?1 AND revoked_at IS NULL",
+                params![id, Utc::now().timestamp_millis()],
+            )?;
+
+            Ok(revoked > 0)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::Cursor;
+    use tempfile::TempDir;
+
+    /// A store on a fresh database file, removed with the directory.
+    fn store() -> (TempDir, SqliteStore) {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SqliteStore::open(dir.path().join("advices.db")).unwrap();
+        (dir, store)
+    }
+
+    fn advice(id: i64, text: &str, created_at: i64) -> Advice {
+        let mut advice = Advice::new(id, text, "test");
+        advice.created_at = Utc.timestamp_millis_opt(created_at).unwrap();
+        advice.updated_at = advice.created_at;
+        advice
+    }
+
+    #[tokio::test]
+    async fn migrates_databases_from_an_old_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("advices.db");
+        {
+            let conn = Connection::open(&path).unwrap();
+            conn.execute_batch(&MIGRATIONS[..3].concat()).unwrap();
+            conn.pragma_update(None, "user_version", 3).unwrap();
+            conn.execute(
+                "INSERT INTO advices (id, advice, created_at) VALUES (7, 'Old advice', 1000)",
+                [],
+            )
+            .unwrap();
+        }
+
+        let store = SqliteStore::open(&path).unwrap();
+        let version: usize = store
+            .conn
+            .lock()
+            .unwrap()
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+
+        let advice = store.get(7).await.unwrap().unwrap();
+        assert_eq!(advice.advice, "Old advice");
+        assert_eq!(advice.source, "unknown");
+        assert_eq!(advice.version, 1);
+        assert!(advice.tags.is_empty());
+        assert_eq!(advice.created_by, None);
+
+        // Opening again applies nothing.
+        drop(store);
+        SqliteStore::open(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn tags_round_trip_sorted() {
+        let (_dir, store) = store();
+        let mut tagged = advice(1, "Tagged", 0);
+        tagged.tags = vec!["work".to_owned(), "life".to_owned(), "code".to_owned()];
+        store.insert(tagged).await.unwrap();
+        store.insert(advice(2, "Untagged", 0)).await.unwrap();
+
+        let tagged = store.get(1).await.unwrap().unwrap();
+        assert_eq!(tagged.tags, ["code", "life", "work"]);
+        assert!(store.get(2).await.unwrap().unwrap().tags.is_empty());
+
+        let page = store.list(&ListQuery::default()).await.unwrap();
+        assert_eq!(page.advices[0].tags, ["code", "life", "work"]);
+        assert!(page.advices[1].tags.is_empty());
+    }
+
+    #[tokio::test]
+    async fn local_ids_start_above_upstream_ids() {
+        let (_dir, store) = store();
+        store.insert(advice(5, "Upstream", 0)).await.unwrap();
+
+        let first = store.insert_local(advice(0, "First", 0)).await.unwrap();
+        assert_eq!(first.id, LOCAL_ID_START);
+        assert_eq!(first.version, 1);
+        let second = store.insert_local(advice(0, "Second", 0)).await.unwrap();
+        assert_eq!(second.id, LOCAL_ID_START + 1);
+
+        // Imports may store local ids of their own; later ones follow them.
+        store
+            .insert(advice(LOCAL_ID_START + 10, "Imported", 0))
+            .await
+            .unwrap();
+        let third = store.insert_local(advice(0, "Third", 0)).await.unwrap();
+        assert_eq!(third.id, LOCAL_ID_START + 11);
+
+        assert!(matches!(
+            store.insert_local(advice(0, " first ", 0)).await,
+            Err(Error::Conflict(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn cursors_page_through_every_sort() {
+        let (_dir, store) = store();
+        // Ties on creation time, and creation order unlike id order.
+        let advices = [(1, 300), (2, 100), (3, 200), (4, 100), (5, 300)];
+        for (id, created_at) in advices {
+            store
+                .insert(advice(id, &format!("Advice {}", id), created_at))
+                .await
+                .unwrap();
+        }
+
+        for sort in [
+            Sort::IdAsc,
+            Sort::IdDesc,
+            Sort::CreatedAsc,
+            Sort::CreatedDesc,
+        ] {
+            let mut expected = advices
+                .iter()
+                .map(|&(id, created_at)| advice(id, "", created_at))
+                .collect::<Vec<_>>();
+            expected.sort_by(|a, b| sort.compare(a, b));
+            let expected = expected.iter().map(|advice| advice.id).collect::<Vec<_>>();
+
+            let mut ids = Vec::new();
+            let mut after: Option<Cursor> = None;
+            loop {
+                let page = store
+                    .list(&ListQuery {
+                        limit: 2,
+                        sort,
+                        after,
+                        ..ListQuery::default()
+                    })
+                    .await
+                    .unwrap();
+                ids.extend(page.advices.iter().map(|advice| advice.id));
+                match page.next_cursor {
+                    Some(cursor) => after = Some(cursor),
+                    None => break,
+                }
+            }
+            assert_eq!(ids, expected, "{:?}", sort);
+        }
+    }
+
+    #[tokio::test]
+    async fn deleting_a_tag_bumps_the_advices_carrying_it() {
+        let (_dir, store) = store();
+        for id in 1..=3 {
+            let mut advice = advice(id, &format!("Advice {}", id), 0);
+            if id != 3 {
+                advice.tags = vec!["gone".to_owned(), "kept".to_owned()];
+            }
+            store.insert(advice).await.unwrap();
+        }
+
+        assert!(store.delete_tag("gone").await.unwrap());
+        for id in 1..=2 {
+            let advice = store.get(id).await.unwrap().unwrap();
+            assert_eq!(advice.tags, ["kept"]);
+            assert_eq!(advice.version, 2);
+        }
+        assert_eq!(store.get(3).await.unwrap().unwrap().version, 1);
+        assert!(store
+            .tags()
+            .await
+            .unwrap()
+            .iter()
+            .all(|tag| tag.name != "gone"));
+
+        assert!(!store.delete_tag("gone").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn daily_picks_are_only_replaced_when_stale() {
+        let (_dir, store) = store();
+        assert_eq!(store.daily_pick("2024-01-01 UTC").await.unwrap(), None);
+
+        assert_eq!(
+            store
+                .record_daily_pick("2024-01-01 UTC", 1, None)
+                .await
+                .unwrap(),
+            1
+        );
+        // Another replica's pick stands.
+        assert_eq!(
+            store
+                .record_daily_pick("2024-01-01 UTC", 2, None)
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            store
+                .record_daily_pick("2024-01-01 UTC", 2, Some(3))
+                .await
+                .unwrap(),
+            1
+        );
+        // Unless it is the one found deleted.
+        assert_eq!(
+            store
+                .record_daily_pick("2024-01-01 UTC", 2, Some(1))
+                .await
+                .unwrap(),
+            2
+        );
+        assert_eq!(store.daily_pick("2024-01-01 UTC").await.unwrap(), Some(2));
+        assert_eq!(
+            store.daily_pick("2024-01-01 Europe/Paris").await.unwrap(),
+            None
+        );
+    }
+}
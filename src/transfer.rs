@@ -0,0 +1,605 @@
+//! Bulk import and export of advices as JSON arrays, JSON Lines or CSV.
+//!
+//! Both directions work incrementally: exports are encoded one page of
+//! advices at a time, and imports are split into records as the body
+//! arrives, so neither side holds the whole collection in memory.
+
+use crate::{Advice, Error, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::mem;
+
+/// Wire format of an import or export.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    JsonLines,
+    Csv,
+}
+
+impl Format {
+    /// Picks the format named by a `Content-Type` header. JSON is assumed
+    /// when there is none.
+    pub fn from_content_type(content_type: Option<&str>) -> Result<Self> {
+        let content_type = match content_type {
+            Some(content_type) => content_type,
+            None => return Ok(Format::Json),
+        };
+
+        Self::from_media_type(content_type).ok_or_else(|| {
+            Error::UnsupportedMediaType(format!(
+                "cannot import {:?}, use application/json, application/x-ndjson or text/csv",
+                content_type
+            ))
+        })
+    }
+
+    /// Picks the first supported format listed in an `Accept` header, in the
+    /// client's order. Parameters such as `q` are ignored. JSON is assumed
+    /// when there is no header or it accepts anything.
+    pub fn from_accept(accept: Option<&str>) -> Result<Self> {
+        let accept = match accept {
+            Some(accept) => accept,
+            None => return Ok(Format::Json),
+        };
+
+        for media_type in accept.split(',') {
+            let media_type = media_type.split(';').next().unwrap_or_default().trim();
+            if media_type == "*/*" || media_type == "application/*" {
+                return Ok(Format::Json);
+            }
+            if let Some(format) = Self::from_media_type(media_type) {
+                return Ok(format);
+            }
+        }
+
+        Err(Error::NotAcceptable(format!(
+            "none of {:?} can be exported, use application/json, \
+             application/x-ndjson or text/csv",
+            accept
+        )))
+    }
+
+    fn from_media_type(media_type: &str) -> Option<Self> {
+        let media_type = media_type.split(';').next()?.trim().to_ascii_lowercase();
+        match media_type.as_str() {
+            "application/json" => Some(Format::Json),
+            "application/x-ndjson" | "application/jsonl" | "application/json-lines" => {
+                Some(Format::JsonLines)
+            }
+            "text/csv" => Some(Format::Csv),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::JsonLines => "application/x-ndjson",
+            Format::Csv => "text/csv; charset=utf-8",
+        }
+    }
+}
+
+/// Source of imported advices that don't name one.
+pub const SOURCE_IMPORT: &str = "import";
+
+/// Columns of the CSV format, in order.
+const CSV_HEADERS: [&str; 9] = [
+    "id",
+    "advice",
+    "source",
+    "tags",
+    "author",
+    "created_by",
+    "created_at",
+    "updated_at",
+    "version",
+];
+
+/// Separates tags within the `tags` CSV column. Tags cannot contain it.
+const CSV_TAG_SEPARATOR: char = ';';
+
+/// Advice flattened into a CSV row.
+#[derive(Debug, Serialize, Deserialize)]
+struct CsvAdvice {
+    id: i64,
+    advice: String,
+    #[serde(default)]
+    source: String,
+    #[serde(default)]
+    tags: String,
+    #[serde(default)]
+    author: Option<String>,
+    #[serde(default)]
+    created_by: Option<String>,
+    #[serde(default = "Utc::now")]
+    created_at: DateTime<Utc>,
+    #[serde(default = "Utc::now")]
+    updated_at: DateTime<Utc>,
+    #[serde(default)]
+    version: u64,
+}
+
+impl From<&Advice> for CsvAdvice {
+    fn from(advice: &Advice) -> Self {
+        Self {
+            id: advice.id,
+            advice: advice.advice.clone(),
+            source: advice.source.clone(),
+            tags: advice.tags.join(&CSV_TAG_SEPARATOR.to_string()),
+            author: advice.author.clone(),
+            created_by: advice.created_by.clone(),
+            created_at: advice.created_at,
+            updated_at: advice.updated_at,
+            version: advice.version,
+        }
+    }
+}
+
+impl From<CsvAdvice> for Advice {
+    fn from(row: CsvAdvice) -> Self {
+        Self {
+            id: row.id,
+            advice: row.advice,
+            source: row.source,
+            tags: row
+                .tags
+                .split(CSV_TAG_SEPARATOR)
+                .filter(|tag| !tag.trim().is_empty())
+                .map(str::to_owned)
+                .collect(),
+            author: row.author,
+            created_by: row.created_by,
+            created_at: row.created_at,
+            updated_at: row.updated_at,
+            version: row.version,
+        }
+    }
+}
+
+/// Encodes advices in a format, a batch at a time.
+#[derive(Debug)]
+pub struct Encoder {
+    format: Format,
+    written: usize,
+}
+
+impl Encoder {
+    pub fn new(format: Format) -> Self {
+        Self { format, written: 0 }
+    }
+
+    /// What goes before the first advice.
+    pub fn start(&self) -> Vec<u8> {
+        match self.format {
+            Format::Json => b"[".to_vec(),
+            Format::JsonLines => Vec::new(),
+            Format::Csv => {
+                let mut line = CSV_HEADERS.join(",");
+                line.push_str("\r\n");
+                line.into_bytes()
+            }
+        }
+    }
+
+    pub fn encode(&mut self, advices: &[Advice]) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        match self.format {
+            Format::Json => {
+                for advice in advices {
+                    if self.written > 0 {
+                        out.push(b',');
+                    }
+                    serde_json::to_writer(&mut out, advice).map_err(anyhow::Error::from)?;
+                    self.written += 1;
+                }
+            }
+            Format::JsonLines => {
+                for advice in advices {
+                    serde_json::to_writer(&mut out, advice).map_err(anyhow::Error::from)?;
+                    out.push(b'\n');
+                    self.written += 1;
+                }
+            }
+            Format::Csv => {
+                let mut writer = csv::WriterBuilder::new()
+                    .has_headers(false)
+                    .from_writer(&mut out);
+                for advice in advices {
+                    writer
+                        .serialize(CsvAdvice::from(advice))
+                        .map_err(anyhow::Error::from)?;
+                    self.written += 1;
+                }
+                writer.flush().map_err(anyhow::Error::from)?;
+            }
+        }
+
+        Ok(out)
+    }
+
+    /// What goes after the last advice.
+    pub fn finish(&self) -> Vec<u8> {
+        match self.format {
+            Format::Json => b"]".to_vec(),
+            Format::JsonLines | Format::Csv => Vec::new(),
+        }
+    }
+}
+
+/// Splits an import body into records as its chunks arrive and decodes them.
+///
+/// Framing errors, such as a JSON body that is not an array, cannot be
+/// recovered from and end the import. Errors in a single record only affect
+/// that record.
+#[derive(Debug)]
+pub struct Decoder {
+    format: Format,
+    buf: Vec<u8>,
+    /// Bytes of `buf` already scanned for the end of the current record.
+    scanned: usize,
+    /// Scanner state carried over between chunks.
+    in_string: bool,
+    escaped: bool,
+    depth: usize,
+    /// Whether the opening `[` of a JSON array has been seen.
+    opened: bool,
+    /// Whether the closing `]` of a JSON array has been seen.
+    closed: bool,
+    /// Column names from the first CSV record.
+    headers: Option<csv::StringRecord>,
+}
+
+/// Longest record accepted, so a body without separators cannot exhaust
+/// memory.
+const MAX_RECORD_LENGTH: usize = 64 * 1024;
+
+impl Decoder {
+    pub fn new(format: Format) -> Self {
+        Self {
+            format,
+            buf: Vec::new(),
+            scanned: 0,
+            in_string: false,
+            escaped: false,
+            depth: 0,
+            opened: false,
+            closed: false,
+            headers: None,
+        }
+    }
+
+    /// Appends a chunk of the body and returns the records it completes.
+    pub fn push(&mut self, chunk: &[u8]) -> Result<Vec<Vec<u8>>> {
+        self.buf.extend_from_slice(chunk);
+
+        // Records completed by this chunk are checked too, as they may have
+        // gone past the limit within it.
+        let mut records = Vec::new();
+        while let Some(record) = self.next_record()? {
+            if record.len() > MAX_RECORD_LENGTH {
+                return Err(record_too_long());
+            }
+            records.push(record);
+        }
+        if self.buf.len() > MAX_RECORD_LENGTH {
+            return Err(record_too_long());
+        }
+
+        Ok(records)
+    }
+
+    /// Returns the last record once the body has ended.
+    pub fn finish(&mut self) -> Result<Option<Vec<u8>>> {
+        match self.format {
+            Format::Json => {
+                if !self.closed {
+                    return Err(Error::BadRequest("JSON array is not terminated".to_owned()));
+                }
+                Ok(None)
+            }
+            Format::JsonLines | Format::Csv => {
+                if self.in_string {
+                    return Err(Error::BadRequest(
+                        "CSV quoted field is not terminated".to_owned(),
+                    ));
+                }
+                let record = mem::take(&mut self.buf);
+                Ok(Some(record).filter(|record| !is_blank(record)))
+            }
+        }
+    }
+
+    /// Decodes and validates one record. Returns `None` for records holding
+    /// no advice, like blank lines and the CSV header.
+    pub fn decode(&mut self, record: &[u8]) -> Result<Option<Advice>> {
+        self.parse(record)?.map(validate).transpose()
+    }
+
+    fn parse(&mut self, record: &[u8]) -> Result<Option<Advice>> {
+        if is_blank(record) {
+            return Ok(None);
+        }
+
+        match self.format {
+            Format::Json | Format::JsonLines => serde_json::from_slice(record)
+                .map(Some)
+                .map_err(|error| Error::BadRequest(error.to_string())),
+            Format::Csv => {
+                let mut reader = csv::ReaderBuilder::new()
+                    .has_headers(false)
+                    .from_reader(record);
+                let row = match reader.records().next() {
+                    Some(row) => row.map_err(|error| Error::BadRequest(error.to_string()))?,
+                    None => return Ok(None),
+                };
+
+                let headers = match &self.headers {
+                    Some(headers) => headers,
+                    None => {
+                        self.headers = Some(row);
+                        return Ok(None);
+                    }
+                };
+                row.deserialize::<CsvAdvice>(Some(headers))
+                    .map(|row| Some(row.into()))
+                    .map_err(|error| Error::BadRequest(error.to_string()))
+            }
+        }
+    }
+
+    fn next_record(&mut self) -> Result<Option<Vec<u8>>> {
+        match self.format {
+            Format::Json => self.next_json_element(),
+            Format::JsonLines | Format::Csv => Ok(self.next_line()),
+        }
+    }
+
+    /// Splits at the next newline, ignoring newlines inside quoted CSV
+    /// fields. Quotes are escaped by doubling them in CSV, which toggles the
+    /// state twice and needs no special case.
+    fn next_line(&mut self) -> Option<Vec<u8>> {
+        let quoted = self.format == Format::Csv;
+        while self.scanned < self.buf.len() {
+            let byte = self.buf[self.scanned];
+            self.scanned += 1;
+            match byte {
+                b'"' if quoted => self.in_string = !self.in_string,
+                b'\n' if !self.in_string => return Some(self.take(self.scanned)),
+                _ => {}
+            }
+        }
+
+        None
+    }
+
+    /// Splits at the next comma or bracket ending a top-level array element.
+    fn next_json_element(&mut self) -> Result<Option<Vec<u8>>> {
+        while self.scanned < self.buf.len() {
+            let byte = self.buf[self.scanned];
+            self.scanned += 1;
+
+            if self.closed {
+                if !byte.is_ascii_whitespace() {
+                    return Err(Error::BadRequest(
+                        "unexpected data after the JSON array".to_owned(),
+                    ));
+                }
+                continue;
+            }
+            if !self.opened {
+                match byte {
+                    b'[' => {
+                        self.opened = true;
+                        self.take(self.scanned);
+                    }
+                    byte if byte.is_ascii_whitespace() => {}
+                    _ => {
+                        return Err(Error::BadRequest(
+                            "expected a JSON array of advices".to_owned(),
+                        ))
+                    }
+                }
+                continue;
+            }
+
+            if self.in_string {
+                match byte {
+                    _ if self.escaped => self.escaped = false,
+                    b'\\' => self.escaped = true,
+                    b'"' => self.in_string = false,
+                    _ => {}
+                }
+                continue;
+            }
+            match byte {
+                b'"' => self.in_string = true,
+                b'{' | b'[' => self.depth += 1,
+                b'}' | b']' if self.depth > 0 => self.depth -= 1,
+                b',' | b']' if self.depth == 0 => {
+                    self.closed = byte == b']';
+                    let mut record = self.take(self.scanned);
+                    record.pop();
+                    if is_blank(&record) && !self.closed {
+                        return Err(Error::BadRequest(
+                            "empty element in the JSON array".to_owned(),
+                        ));
+                    }
+                    return Ok(Some(record));
+                }
+                _ => {}
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Removes and returns the first `len` bytes of the buffer.
+    fn take(&mut self, len: usize) -> Vec<u8> {
+        let rest = self.buf.split_off(len);
+        self.scanned = 0;
+        mem::replace(&mut self.buf, rest)
+    }
+}
+
+fn record_too_long() -> Error {
+    Error::BadRequest(format!(
+        "records must be at most {} bytes",
+        MAX_RECORD_LENGTH
+    ))
+}
+
+/// Applies the checks of advices submitted through the API. Imported advices
+/// keep their id and timestamps.
+fn validate(mut advice: Advice) -> Result<Advice> {
+    advice.advice = Advice::validate_text(&advice.advice)?;
+    advice.tags = Advice::validate_tags(&advice.tags)?;
+    advice.author = match &advice.author {
+        Some(author) => Advice::validate_author(author)?,
+        None => None,
+    };
+    if advice.source.is_empty() {
+        advice.source = SOURCE_IMPORT.to_owned();
+    }
+
+    Ok(advice)
+}
+
+fn is_blank(bytes: &[u8]) -> bool {
+    bytes.iter().all(u8::is_ascii_whitespace)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds `body` to a decoder in chunks of `size` bytes and returns every
+    /// record, the one `finish` returns included.
+    fn split(format: Format, body: &str, size: usize) -> Result<Vec<String>> {
+        let mut decoder = Decoder::new(format);
+        let mut records = Vec::new();
+        for chunk in body.as_bytes().chunks(size) {
+            records.extend(decoder.push(chunk)?);
+        }
+        records.extend(decoder.finish()?);
+
+        Ok(records
+            .into_iter()
+            .map(|record| String::from_utf8(record).unwrap())
+            .collect())
+    }
+
+    /// Checks that `body` splits into `expected` however it is chunked.
+    fn assert_splits(format: Format, body: &str, expected: &[&str]) {
+        for size in 1..=body.len() {
+            assert_eq!(
+                split(format, body, size).unwrap(),
+                expected,
+                "chunks of {}",
+                size
+            );
+        }
+    }
+
+    #[test]
+    fn json_elements_split_at_top_level_commas() {
+        assert_splits(
+            Format::Json,
+            r#" [{"advice": "a, b", "tags": ["x", "y"]}, {"advice": "c"}] "#,
+            &[
+                r#"{"advice": "a, b", "tags": ["x", "y"]}"#,
+                r#" {"advice": "c"}"#,
+            ],
+        );
+    }
+
+    #[test]
+    fn json_strings_hide_brackets_and_escaped_quotes() {
+        assert_splits(
+            Format::Json,
+            r#"[{"advice": "}], \"[{\\"}, {"advice": "\\"}]"#,
+            &[r#"{"advice": "}], \"[{\\"}"#, r#" {"advice": "\\"}"#],
+        );
+    }
+
+    #[test]
+    fn json_empty_array_has_a_blank_record() {
+        assert_splits(Format::Json, "[ ]", &[" "]);
+    }
+
+    #[test]
+    fn json_rejects_malformed_arrays() {
+        assert!(matches!(
+            split(Format::Json, r#"[{"advice": "a"}"#, 4),
+            Err(Error::BadRequest(_))
+        ));
+        assert!(matches!(
+            split(Format::Json, r#"[{"advice": "a"}] x"#, 4),
+            Err(Error::BadRequest(_))
+        ));
+        assert!(matches!(
+            split(Format::Json, r#"{"advice": "a"}"#, 4),
+            Err(Error::BadRequest(_))
+        ));
+        assert!(matches!(
+            split(Format::Json, r#"[{"advice": "a"},,]"#, 4),
+            Err(Error::BadRequest(_))
+        ));
+    }
+
+    #[test]
+    fn json_lines_keep_the_last_line_without_newline() {
+        assert_splits(
+            Format::JsonLines,
+            "{\"advice\": \"a\"}\n\n{\"advice\": \"b\"}",
+            &["{\"advice\": \"a\"}\n", "\n", "{\"advice\": \"b\"}"],
+        );
+    }
+
+    #[test]
+    fn csv_quoted_fields_keep_newlines_and_quotes() {
+        assert_splits(
+            Format::Csv,
+            "id,advice\n1,\"one\ntwo, \"\"three\"\"\"\n2,four\n",
+            &["id,advice\n", "1,\"one\ntwo, \"\"three\"\"\"\n", "2,four\n"],
+        );
+    }
+
+    #[test]
+    fn csv_rejects_unterminated_quoted_fields() {
+        assert!(matches!(
+            split(Format::Csv, "id,advice\n1,\"one\n", 3),
+            Err(Error::BadRequest(_))
+        ));
+    }
+
+    #[test]
+    fn records_are_limited_in_length() {
+        let line = "x".repeat(MAX_RECORD_LENGTH - 1);
+        let body = format!("{}\n{}\n", line, line);
+        assert_eq!(split(Format::JsonLines, &body, 4096).unwrap().len(), 2);
+
+        let line = "x".repeat(MAX_RECORD_LENGTH + 1);
+        assert!(matches!(
+            split(Format::JsonLines, &line, 4096),
+            Err(Error::BadRequest(_))
+        ));
+
+        let element = format!("\"{}\"", "x".repeat(MAX_RECORD_LENGTH));
+        assert!(matches!(
+            split(Format::Json, &format!("[{}]", element), 4096),
+            Err(Error::BadRequest(_))
+        ));
+    }
+
+    #[test]
+    fn csv_header_names_the_columns() {
+        let mut decoder = Decoder::new(Format::Csv);
+        assert!(decoder.decode(b"advice,id\n").unwrap().is_none());
+
+        let advice = decoder.decode(b"\"one, two\",7\n").unwrap().unwrap();
+        assert_eq!(advice.id, 7);
+        assert_eq!(advice.advice, "one, two");
+    }
+}